@@ -0,0 +1,41 @@
+//!
+//! Compile the bundled OUI registry into a sorted prefix table
+//!
+//!
+
+#[path = "src/database/registry.rs"]
+mod registry;
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+static REGISTRY_CSV: &str = "src/database/oui.csv";
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", REGISTRY_CSV);
+    println!("cargo:rerun-if-changed=src/database/registry.rs");
+
+    let mut contents = String::new();
+    File::open(REGISTRY_CSV)
+        .expect("Cannot open OUI registry")
+        .read_to_string(&mut contents)
+        .expect("Cannot read OUI registry");
+
+    let (prefixes, orgs) = registry::build_table(registry::parse_registry(&contents));
+
+    let out_path = Path::new(&std::env::var("OUT_DIR").unwrap()).join("oui_table.rs");
+    let mut out = File::create(out_path).expect("Cannot create OUI table");
+
+    writeln!(out, "static OUI_ORGS: &[&str] = &[").unwrap();
+    for org in &orgs {
+        writeln!(out, "    {:?},", org).unwrap();
+    }
+    writeln!(out, "];").unwrap();
+
+    writeln!(out, "static OUI_PREFIXES: &[(u8, u64, u32)] = &[").unwrap();
+    for (bits, prefix, org) in &prefixes {
+        writeln!(out, "    ({}, 0x{:x}, {}),", bits, prefix, org).unwrap();
+    }
+    writeln!(out, "];").unwrap();
+}
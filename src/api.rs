@@ -11,6 +11,8 @@ use serialport::prelude::*;
 use std::convert::TryFrom;
 
 pub static BROADCAST_ADDR: u64 = 0xffff;
+/// ADC reference of the DigiMesh modules, in millivolts
+pub static DEFAULT_VREF_MV: u32 = 1200;
 
 static DELIM: u8 = 0x7e;
 
@@ -54,23 +56,37 @@ pub type Result<T> = std::result::Result<T, Error>;
 #[derive(Debug, PartialEq)]
 pub enum FrameId {
     TransmitRequest,
+    ReceivePacket,
+    ExplicitAddressing,
+    ExplicitRxIndicator,
     TransmitStatus,
     AtCommand,
+    AtCommandQueue,
     AtCommandResponse,
     RemoteAtCommand,
     RemoteAtCommandResponse,
+    RouteInformation,
+    ModemStatus,
+    IoDataSample,
     Null,
 }
 
 impl FrameId {
-    fn id(&self) -> u8 {
+    pub fn id(&self) -> u8 {
         match *self {
-            FrameId::TransmitRequest => 0x90,
+            FrameId::TransmitRequest => 0x10,
+            FrameId::ReceivePacket => 0x90,
+            FrameId::ExplicitAddressing => 0x11,
+            FrameId::ExplicitRxIndicator => 0x91,
             FrameId::TransmitStatus => 0x8b,
             FrameId::AtCommand => 0x08,
+            FrameId::AtCommandQueue => 0x09,
             FrameId::AtCommandResponse => 0x88,
             FrameId::RemoteAtCommand => 0x17,
             FrameId::RemoteAtCommandResponse => 0x97,
+            FrameId::RouteInformation => 0x8d,
+            FrameId::ModemStatus => 0x8a,
+            FrameId::IoDataSample => 0x92,
             FrameId::Null => 0xff,
         }
     }
@@ -86,6 +102,11 @@ pub trait RecieveApiFrame: std::fmt::Debug + DowncastSync {
         println!("{:#x?}", self);
     }
     fn payload(&self) -> Result<BytesMut>;
+
+    /// frame id of the request this frame answers, if it is a response
+    fn request_frame_id(&self) -> Option<u8> {
+        None
+    }
 }
 
 impl_downcast!(sync RecieveApiFrame);
@@ -114,12 +135,66 @@ pub trait TransmitApiFrame {
     }
 
     fn gen_frame_id(&self) -> u8 {
+        // frame id 0 tells the module not to send a response
         let mut rng = rand::thread_rng();
-        let r: u8 = rng.gen();
+        let r: u8 = rng.gen_range(1, 0xff);
         r
     }
 }
 
+/// Read one complete API frame, from the start delimiter up to and including
+/// the checksum. Bytes preceding the delimiter are discarded.
+pub fn read_frame(ser: &mut dyn SerialPort) -> Result<BytesMut> {
+    let mut mini_buf: [u8; 1] = [0];
+    loop {
+        ser.read_exact(&mut mini_buf)?;
+        if mini_buf[0] == DELIM {
+            break;
+        }
+    }
+
+    let mut len_buf: [u8; 2] = [0; 2];
+    ser.read_exact(&mut len_buf)?;
+    let len = u16::from_be_bytes(len_buf) as usize;
+
+    let mut body = vec![0; len + 1];
+    ser.read_exact(&mut body[..])?;
+
+    let mut frame = BytesMut::with_capacity(len + 4);
+    frame.put_u8(DELIM);
+    frame.put(&len_buf[..]);
+    frame.put(&body[..]);
+
+    let checksum = body.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+    if checksum != 0xff {
+        return Err(Error::FrameError("Invalid frame checksum".to_string()));
+    }
+
+    Ok(frame)
+}
+
+/// Decode a frame returned by `read_frame` into its typed representation
+pub fn decode_frame(frame: BytesMut) -> Result<Box<dyn RecieveApiFrame>> {
+    if frame.len() < 5 {
+        return Err(Error::FrameError("Frame too short".to_string()));
+    }
+
+    match frame[3] {
+        0x88 => Ok(Box::new(AtCommandResponse::from_frame(frame)?)),
+        0x97 => Ok(Box::new(RemoteAtCommandResponse::from_frame(frame)?)),
+        0x8a => Ok(Box::new(ModemStatus::from_frame(frame)?)),
+        0x8b => Ok(Box::new(TransmitStatus::from_frame(frame)?)),
+        0x8d => Ok(Box::new(RouteInformation::from_frame(frame)?)),
+        0x90 => Ok(Box::new(ReceivePacket::from_frame(frame)?)),
+        0x91 => Ok(Box::new(ExplicitRxIndicator::from_frame(frame)?)),
+        0x92 => Ok(Box::new(IoDataSample::from_frame(frame)?)),
+        other => Err(Error::FrameError(format!(
+            "Unsupported frame type 0x{:02x}",
+            other
+        ))),
+    }
+}
+
 /**
  * AtCommand Support
  *
@@ -128,7 +203,8 @@ pub trait TransmitApiFrame {
 pub struct AtCommand<'a> {
     pub command: &'a str,
     pub parameter: &'a Option<&'a [u8]>,
-    pub rcr_len: usize, // the number of carriage returns in the reponse for this command
+    pub multi_line: bool, // the response ends with an empty line instead of the first carriage return
+    pub timeout: std::time::Duration,
 }
 
 #[derive(Debug)]
@@ -139,29 +215,34 @@ pub enum AtCommands<'a> {
 }
 
 impl AtCommands<'_> {
-    pub fn create(&self) -> AtCommand {
+    pub fn create(&self) -> AtCommand<'_> {
+        let timeout = std::time::Duration::from_secs(3);
         match *self {
             AtCommands::CmdMode(ref state) => match state {
                 true => AtCommand {
                     command: "+++",
                     parameter: &None,
-                    rcr_len: 1,
+                    multi_line: false,
+                    timeout,
                 },
                 false => AtCommand {
                     command: "CN",
                     parameter: &None,
-                    rcr_len: 1,
+                    multi_line: false,
+                    timeout,
                 },
             },
             AtCommands::Discover(ref param) => AtCommand {
-                command: "AtCommandFrame",
+                command: "ND",
                 parameter: param,
-                rcr_len: 10 + 1,
+                multi_line: true,
+                timeout: std::time::Duration::from_secs(15), // NT default is 13 s
             },
             AtCommands::AtCmd((ref cmd, ref param)) => AtCommand {
                 command: cmd,
                 parameter: param,
-                rcr_len: 1,
+                multi_line: false,
+                timeout,
             },
         }
     }
@@ -189,8 +270,198 @@ impl RecieveApiFrame for NullRecieve {
     }
 }
 
+/************ Modem Status **********************/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModemStatusKind {
+    HardwareReset,
+    WatchdogReset,
+    JoinedNetwork,
+    Disassociated,
+    CoordinatorStarted,
+    SecurityKeyUpdated,
+    NetworkWoke,
+    NetworkSleep,
+    VoltageLimitExceeded,
+    ConfigChangedWhileJoining,
+    KeyEstablished,
+    SecureSessionEstablished,
+    SecureSessionEnded,
+    StackError(u8),
+    Unknown(u8),
+}
+
+impl ModemStatusKind {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0x00 => ModemStatusKind::HardwareReset,
+            0x01 => ModemStatusKind::WatchdogReset,
+            0x02 => ModemStatusKind::JoinedNetwork,
+            0x03 => ModemStatusKind::Disassociated,
+            0x06 => ModemStatusKind::CoordinatorStarted,
+            0x07 => ModemStatusKind::SecurityKeyUpdated,
+            0x0b => ModemStatusKind::NetworkWoke,
+            0x0c => ModemStatusKind::NetworkSleep,
+            0x0d => ModemStatusKind::VoltageLimitExceeded,
+            0x10 => ModemStatusKind::KeyEstablished,
+            0x11 => ModemStatusKind::ConfigChangedWhileJoining,
+            0x3b => ModemStatusKind::SecureSessionEstablished,
+            0x3c => ModemStatusKind::SecureSessionEnded,
+            code if code >= 0x80 => ModemStatusKind::StackError(code),
+            other => ModemStatusKind::Unknown(other),
+        }
+    }
+
+    /// the module restarted and lost its runtime state
+    pub fn is_reset(&self) -> bool {
+        *self == ModemStatusKind::HardwareReset || *self == ModemStatusKind::WatchdogReset
+    }
+}
+
+impl std::fmt::Display for ModemStatusKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            ModemStatusKind::HardwareReset => write!(f, "Hardware reset"),
+            ModemStatusKind::WatchdogReset => write!(f, "Watchdog timer reset"),
+            ModemStatusKind::JoinedNetwork => write!(f, "Joined network"),
+            ModemStatusKind::Disassociated => write!(f, "Disassociated"),
+            ModemStatusKind::CoordinatorStarted => write!(f, "Coordinator started"),
+            ModemStatusKind::SecurityKeyUpdated => write!(f, "Network security key updated"),
+            ModemStatusKind::NetworkWoke => write!(f, "Network woke up"),
+            ModemStatusKind::NetworkSleep => write!(f, "Network went to sleep"),
+            ModemStatusKind::VoltageLimitExceeded => write!(f, "Voltage supply limit exceeded"),
+            ModemStatusKind::ConfigChangedWhileJoining => {
+                write!(f, "Modem configuration changed while join in progress")
+            }
+            ModemStatusKind::KeyEstablished => write!(f, "Key established"),
+            ModemStatusKind::SecureSessionEstablished => write!(f, "Secure session established"),
+            ModemStatusKind::SecureSessionEnded => write!(f, "Secure session ended"),
+            ModemStatusKind::StackError(code) => write!(f, "Stack error 0x{:02x}", code),
+            ModemStatusKind::Unknown(code) => write!(f, "Unknown modem status 0x{:02x}", code),
+        }
+    }
+}
+
+/// Unsolicited notification of a change in the module state
+#[derive(Debug, Clone)]
+pub struct ModemStatus {
+    pub status: ModemStatusKind,
+}
+
+impl RecieveApiFrame for ModemStatus {
+    fn id(&self) -> FrameId {
+        FrameId::ModemStatus
+    }
+
+    fn recieve(mut ser: Box<dyn SerialPort>) -> Result<Self> {
+        Self::from_frame(read_frame(&mut *ser)?)
+    }
+
+    fn payload(&self) -> Result<BytesMut> {
+        Err(Error::FrameError(
+            "Modem status frames carry no payload".to_string(),
+        ))
+    }
+}
+
+impl ModemStatus {
+    pub fn from_frame(buffer: BytesMut) -> Result<Self> {
+        if buffer.len() < 6 {
+            return Err(Error::FrameError("Modem status too short".to_string()));
+        }
+        Ok(Self {
+            status: ModemStatusKind::from_code(buffer[4]),
+        })
+    }
+}
+
 /************ Transmit Status **********************/
 
+/// Delivery status field of a Transmit Status frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeliveryStatus {
+    Success,
+    MacAckFailure,
+    CcaFailure,
+    InvalidDestinationEndpoint,
+    NetworkAckFailure,
+    NotJoined,
+    SelfAddressed,
+    AddressNotFound,
+    RouteNotFound,
+    BroadcastRelayNotHeard,
+    ResourceError,
+    PayloadTooLarge,
+    IndirectMessageUnrequested,
+    Unknown(u8),
+}
+
+impl DeliveryStatus {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0x00 => DeliveryStatus::Success,
+            0x01 => DeliveryStatus::MacAckFailure,
+            0x02 => DeliveryStatus::CcaFailure,
+            0x15 => DeliveryStatus::InvalidDestinationEndpoint,
+            0x21 => DeliveryStatus::NetworkAckFailure,
+            0x22 => DeliveryStatus::NotJoined,
+            0x23 => DeliveryStatus::SelfAddressed,
+            0x24 => DeliveryStatus::AddressNotFound,
+            0x25 => DeliveryStatus::RouteNotFound,
+            0x26 => DeliveryStatus::BroadcastRelayNotHeard,
+            0x2c | 0x31 | 0x32 => DeliveryStatus::ResourceError,
+            0x74 => DeliveryStatus::PayloadTooLarge,
+            0x75 => DeliveryStatus::IndirectMessageUnrequested,
+            other => DeliveryStatus::Unknown(other),
+        }
+    }
+}
+
+impl std::fmt::Display for DeliveryStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            DeliveryStatus::Success => write!(f, "Success"),
+            DeliveryStatus::MacAckFailure => write!(f, "MAC ACK failure"),
+            DeliveryStatus::CcaFailure => write!(f, "Collision avoidance failure"),
+            DeliveryStatus::InvalidDestinationEndpoint => write!(f, "Invalid destination endpoint"),
+            DeliveryStatus::NetworkAckFailure => write!(f, "Network ACK failure"),
+            DeliveryStatus::NotJoined => write!(f, "Not joined to network"),
+            DeliveryStatus::SelfAddressed => write!(f, "Self-addressed"),
+            DeliveryStatus::AddressNotFound => write!(f, "Address not found"),
+            DeliveryStatus::RouteNotFound => write!(f, "Route not found"),
+            DeliveryStatus::BroadcastRelayNotHeard => {
+                write!(f, "Broadcast source failed to hear a neighbor relay")
+            }
+            DeliveryStatus::ResourceError => write!(f, "Resource error"),
+            DeliveryStatus::PayloadTooLarge => write!(f, "Data payload too large"),
+            DeliveryStatus::IndirectMessageUnrequested => write!(f, "Indirect message unrequested"),
+            DeliveryStatus::Unknown(code) => write!(f, "Unknown delivery status 0x{:02x}", code),
+        }
+    }
+}
+
+/// Discovery status field of a Transmit Status frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiscoveryStatus {
+    NoOverhead,
+    Address,
+    Route,
+    AddressAndRoute,
+    Unknown(u8),
+}
+
+impl DiscoveryStatus {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0x00 => DiscoveryStatus::NoOverhead,
+            0x01 => DiscoveryStatus::Address,
+            0x02 => DiscoveryStatus::Route,
+            0x03 => DiscoveryStatus::AddressAndRoute,
+            other => DiscoveryStatus::Unknown(other),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct TransmitStatus {
     frame_id: u8,
@@ -205,19 +476,89 @@ impl RecieveApiFrame for TransmitStatus {
         FrameId::TransmitStatus
     }
 
+    fn request_frame_id(&self) -> Option<u8> {
+        Some(self.frame_id)
+    }
+
     fn recieve(mut ser: Box<dyn SerialPort>) -> Result<Self> {
-        // wait for first
-        let mut response: [u8; 11] = [0; 11];
-        ser.read_exact(&mut response)?;
+        Self::from_frame(read_frame(&mut *ser)?)
+    }
+
+    fn payload(&self) -> Result<BytesMut> {
+        match &self.payload {
+            Some(p) => Ok(p.clone()),
+            None => Err(Error::FrameError("Empty payload".to_string())),
+        }
+    }
+}
+
+impl TransmitStatus {
+    pub fn from_frame(response: BytesMut) -> Result<Self> {
+        if response.len() < 11 {
+            return Err(Error::FrameError("Transmit status too short".to_string()));
+        }
         Ok(Self {
             frame_id: response[4],
             transmit_retry_count: response[7],
             deliver_status: response[8],
             discovery_status: response[9],
-            payload: Some(BytesMut::from(&response[..])),
+            payload: Some(response),
         })
     }
 
+    pub fn frame_id(&self) -> u8 {
+        self.frame_id
+    }
+
+    pub fn retry_count(&self) -> u8 {
+        self.transmit_retry_count
+    }
+
+    pub fn delivered(&self) -> bool {
+        self.deliver_status == 0
+    }
+
+    pub fn delivery_status(&self) -> DeliveryStatus {
+        DeliveryStatus::from_code(self.deliver_status)
+    }
+
+    pub fn discovery_status(&self) -> DiscoveryStatus {
+        DiscoveryStatus::from_code(self.discovery_status)
+    }
+}
+
+/********************* Route Information ****************************************/
+
+#[derive(Debug, PartialEq)]
+pub enum RouteEvent {
+    Nack,
+    TraceRoute,
+    Unknown(u8),
+}
+
+/// One hop of a trace route (or NACK) as reported by a Route Information Packet
+#[derive(Debug)]
+pub struct RouteInformation {
+    pub event: RouteEvent,
+    pub timestamp: u32, // microseconds, from the reporting node's clock
+    pub ack_timeout_count: u8,
+    pub tx_blocked_count: u8,
+    pub dest_addr: u64,
+    pub source_addr: u64,
+    pub responder_addr: u64,
+    pub receiver_addr: u64,
+    payload: Option<BytesMut>,
+}
+
+impl RecieveApiFrame for RouteInformation {
+    fn id(&self) -> FrameId {
+        FrameId::RouteInformation
+    }
+
+    fn recieve(mut ser: Box<dyn SerialPort>) -> Result<Self> {
+        Self::from_frame(read_frame(&mut *ser)?)
+    }
+
     fn payload(&self) -> Result<BytesMut> {
         match &self.payload {
             Some(p) => Ok(p.clone()),
@@ -226,8 +567,37 @@ impl RecieveApiFrame for TransmitStatus {
     }
 }
 
+impl RouteInformation {
+    pub fn from_frame(buffer: BytesMut) -> Result<Self> {
+        if buffer.len() < 46 {
+            return Err(Error::FrameError("Route information too short".to_string()));
+        }
+
+        let addr_at =
+            |idx: usize| u64::from_be_bytes(<[u8; 8]>::try_from(&buffer[idx..idx + 8]).unwrap());
+        let event = match buffer[4] {
+            0x11 => RouteEvent::Nack,
+            0x12 => RouteEvent::TraceRoute,
+            other => RouteEvent::Unknown(other),
+        };
+
+        Ok(Self {
+            event,
+            timestamp: u32::from_be_bytes(<[u8; 4]>::try_from(&buffer[6..10]).unwrap()),
+            ack_timeout_count: buffer[10],
+            tx_blocked_count: buffer[11],
+            dest_addr: addr_at(13),
+            source_addr: addr_at(21),
+            responder_addr: addr_at(29),
+            receiver_addr: addr_at(37),
+            payload: Some(buffer),
+        })
+    }
+}
+
 /********************* Transmit Request ****************************************/
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum MessagingMode {
     PointToPoint,
     Repeater,
@@ -282,19 +652,131 @@ impl TransmitApiFrame for TransmitRequestFrame<'_> {
 
     fn gen(&self) -> Result<BytesMut> {
         let mut packet = BytesMut::new();
-        let mut rng = rand::thread_rng();
         if self.payload.len() > 65535 - 112 {
             return Err(Error::PayloadError("Payload exceeds max size".to_string()));
         }
 
-        let frame_id: u8 = rng.gen();
+        let frame_id: u8 = self.gen_frame_id();
 
         packet.put_u8(self.delim());
         packet.put_u16((self.payload.len() as u16) + (0x0e as u16));
-        packet.put_u8(0x10);
+        packet.put_u8(self.id().id());
+        packet.put_u8(frame_id);
+        packet.put_u64(self.dest_addr);
+        packet.put_u16(0xfffe);
+        packet.put_u8(self.broadcast_radius);
+
+        match self.options {
+            Some(opts) => packet.put_u8(opts.compile()),
+            None => packet.put_u8(0),
+        }
+        packet.put(self.payload);
+
+        let chksum = self.calc_checksum(&packet[..])?;
+        packet.put_u8(chksum);
+
+        Ok(packet)
+    }
+}
+
+/********************* Receive Packet ****************************************/
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReceiveOptions {
+    pub acknowledged: bool,
+    pub broadcast: bool,
+    pub encrypted: bool,
+    pub mode: Option<MessagingMode>,
+}
+
+impl ReceiveOptions {
+    pub fn parse(val: u8) -> Self {
+        Self {
+            acknowledged: val & 0x01 != 0,
+            broadcast: val & 0x02 != 0,
+            encrypted: val & 0x20 != 0,
+            mode: match val >> 6 {
+                0x1 => Some(MessagingMode::PointToPoint),
+                0x2 => Some(MessagingMode::Repeater),
+                0x3 => Some(MessagingMode::DigiMesh),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// RF data received from a remote node
+#[derive(Debug, Clone)]
+pub struct ReceivePacket {
+    pub source_addr: u64,
+    pub options: ReceiveOptions,
+    pub data: BytesMut,
+}
+
+impl RecieveApiFrame for ReceivePacket {
+    fn id(&self) -> FrameId {
+        FrameId::ReceivePacket
+    }
+
+    fn recieve(mut ser: Box<dyn SerialPort>) -> Result<Self> {
+        Self::from_frame(read_frame(&mut *ser)?)
+    }
+
+    fn payload(&self) -> Result<BytesMut> {
+        Ok(self.data.clone())
+    }
+}
+
+impl ReceivePacket {
+    pub fn from_frame(buffer: BytesMut) -> Result<Self> {
+        if buffer.len() < 16 {
+            return Err(Error::FrameError("Receive packet too short".to_string()));
+        }
+        let source_addr = u64::from_be_bytes(<[u8; 8]>::try_from(&buffer[4..12]).unwrap());
+        Ok(Self {
+            source_addr,
+            options: ReceiveOptions::parse(buffer[14]),
+            data: BytesMut::from(&buffer[15..buffer.len() - 1]),
+        })
+    }
+}
+
+/********************* Explicit Addressing Command Frame ****************************************/
+
+/// Transmit request with explicit endpoints, cluster and profile
+pub struct ExplicitAddressingFrame<'a> {
+    pub dest_addr: u64,
+    pub src_endpoint: u8,
+    pub dest_endpoint: u8,
+    pub cluster_id: u16,
+    pub profile_id: u16,
+    pub broadcast_radius: u8,
+    pub options: Option<&'a TransmitRequestOptions>,
+    pub payload: &'a [u8],
+}
+
+impl TransmitApiFrame for ExplicitAddressingFrame<'_> {
+    fn id(&self) -> FrameId {
+        FrameId::ExplicitAddressing
+    }
+
+    fn gen(&self) -> Result<BytesMut> {
+        if self.payload.len() > 65535 - 112 {
+            return Err(Error::PayloadError("Payload exceeds max size".to_string()));
+        }
+
+        let mut packet = BytesMut::with_capacity(self.payload.len() + 24);
+        let frame_id: u8 = self.gen_frame_id();
+        packet.put_u8(DELIM);
+        packet.put_u16(0); // length; just to initalize
+        packet.put_u8(self.id().id());
         packet.put_u8(frame_id);
         packet.put_u64(self.dest_addr);
         packet.put_u16(0xfffe);
+        packet.put_u8(self.src_endpoint);
+        packet.put_u8(self.dest_endpoint);
+        packet.put_u16(self.cluster_id);
+        packet.put_u16(self.profile_id);
         packet.put_u8(self.broadcast_radius);
 
         match self.options {
@@ -303,6 +785,9 @@ impl TransmitApiFrame for TransmitRequestFrame<'_> {
         }
         packet.put(self.payload);
 
+        let packet_len = (packet.len() - 3) as u16;
+        packet[1] = (packet_len >> 8) as u8;
+        packet[2] = (packet_len & 0xff) as u8;
         let chksum = self.calc_checksum(&packet[..])?;
         packet.put_u8(chksum);
 
@@ -310,6 +795,217 @@ impl TransmitApiFrame for TransmitRequestFrame<'_> {
     }
 }
 
+/********************* Explicit Rx Indicator ****************************************/
+
+/// RF data received with its endpoints, cluster and profile (AO set to 1)
+#[derive(Debug, Clone)]
+pub struct ExplicitRxIndicator {
+    pub source_addr: u64,
+    pub src_endpoint: u8,
+    pub dest_endpoint: u8,
+    pub cluster_id: u16,
+    pub profile_id: u16,
+    pub options: ReceiveOptions,
+    pub data: BytesMut,
+}
+
+impl RecieveApiFrame for ExplicitRxIndicator {
+    fn id(&self) -> FrameId {
+        FrameId::ExplicitRxIndicator
+    }
+
+    fn recieve(mut ser: Box<dyn SerialPort>) -> Result<Self> {
+        Self::from_frame(read_frame(&mut *ser)?)
+    }
+
+    fn payload(&self) -> Result<BytesMut> {
+        Ok(self.data.clone())
+    }
+}
+
+impl ExplicitRxIndicator {
+    pub fn from_frame(buffer: BytesMut) -> Result<Self> {
+        if buffer.len() < 22 {
+            return Err(Error::FrameError(
+                "Explicit rx indicator too short".to_string(),
+            ));
+        }
+        let source_addr = u64::from_be_bytes(<[u8; 8]>::try_from(&buffer[4..12]).unwrap());
+        Ok(Self {
+            source_addr,
+            src_endpoint: buffer[14],
+            dest_endpoint: buffer[15],
+            cluster_id: u16::from_be_bytes([buffer[16], buffer[17]]),
+            profile_id: u16::from_be_bytes([buffer[18], buffer[19]]),
+            options: ReceiveOptions::parse(buffer[20]),
+            data: BytesMut::from(&buffer[21..buffer.len() - 1]),
+        })
+    }
+}
+
+/********************* IO Data Sample Rx Indicator ****************************************/
+
+/// One set of IO samples, as sent by a node sampling its pins (IR/IC) or
+/// returned by IS. Digital bit n is DIOn, analog bit n is ADn and analog bit 7
+/// is the supply voltage.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IoSample {
+    pub digital_mask: u16,
+    pub analog_mask: u8,
+    pub digital: u16,
+    pub analog: Vec<(u8, u16)>, // (channel, 10-bit reading)
+    pub supply_voltage: Option<u16>,
+}
+
+impl IoSample {
+    /// Parse a sample set starting at the number of samples
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < 4 {
+            return Err(Error::FrameError("IO sample too short".to_string()));
+        }
+        let digital_mask = u16::from_be_bytes([data[1], data[2]]);
+        let analog_mask = data[3];
+        let mut idx = 4;
+
+        let read_u16 = |idx: &mut usize| -> Result<u16> {
+            match data.get(*idx..*idx + 2) {
+                Some(val) => {
+                    *idx += 2;
+                    Ok(u16::from_be_bytes([val[0], val[1]]))
+                }
+                None => Err(Error::FrameError("IO sample truncated".to_string())),
+            }
+        };
+
+        let mut digital = 0;
+        if digital_mask != 0 {
+            digital = read_u16(&mut idx)? & digital_mask;
+        }
+        let mut analog = Vec::new();
+        for channel in 0..7 {
+            if analog_mask & (1 << channel) != 0 {
+                analog.push((channel, read_u16(&mut idx)? & 0x3ff));
+            }
+        }
+        let mut supply_voltage = None;
+        if analog_mask & 0x80 != 0 {
+            supply_voltage = Some(read_u16(&mut idx)?);
+        }
+
+        Ok(Self {
+            digital_mask,
+            analog_mask,
+            digital,
+            analog,
+            supply_voltage,
+        })
+    }
+
+    /// State of DIO`pin`, None if the pin is not sampled as a digital line
+    pub fn digital_state(&self, pin: u8) -> Option<bool> {
+        if pin > 15 || self.digital_mask & (1 << pin) == 0 {
+            return None;
+        }
+        Some(self.digital & (1 << pin) != 0)
+    }
+
+    /// Raw 10-bit reading of AD`channel`
+    pub fn analog_value(&self, channel: u8) -> Option<u16> {
+        self.analog
+            .iter()
+            .find(|(ch, _)| *ch == channel)
+            .map(|(_, val)| *val)
+    }
+
+    /// Reading of AD`channel` converted with the ADC reference `vref_mv`
+    pub fn analog_millivolts(&self, channel: u8, vref_mv: u32) -> Option<u32> {
+        self.analog_value(channel)
+            .map(|val| val as u32 * vref_mv / 1023)
+    }
+
+    /// Supply voltage in millivolts (same unit as %V)
+    pub fn supply_millivolts(&self) -> Option<u32> {
+        self.supply_voltage.map(|val| val as u32)
+    }
+}
+
+/// IO samples received from a remote node
+#[derive(Debug, Clone)]
+pub struct IoDataSample {
+    pub source_addr: u64,
+    pub options: ReceiveOptions,
+    pub sample: IoSample,
+}
+
+impl RecieveApiFrame for IoDataSample {
+    fn id(&self) -> FrameId {
+        FrameId::IoDataSample
+    }
+
+    fn recieve(mut ser: Box<dyn SerialPort>) -> Result<Self> {
+        Self::from_frame(read_frame(&mut *ser)?)
+    }
+
+    fn payload(&self) -> Result<BytesMut> {
+        Err(Error::FrameError(
+            "IO sample frames carry no payload".to_string(),
+        ))
+    }
+}
+
+impl IoDataSample {
+    pub fn from_frame(buffer: BytesMut) -> Result<Self> {
+        if buffer.len() < 20 {
+            return Err(Error::FrameError("IO data sample too short".to_string()));
+        }
+        let source_addr = u64::from_be_bytes(<[u8; 8]>::try_from(&buffer[4..12]).unwrap());
+        Ok(Self {
+            source_addr,
+            options: ReceiveOptions::parse(buffer[14]),
+            sample: IoSample::parse(&buffer[15..buffer.len() - 1])?,
+        })
+    }
+}
+
+/********************* AT Command Status ****************************************/
+
+/// Command status of local and remote AT command responses
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AtStatus {
+    Ok,
+    Error,
+    InvalidCommand,
+    InvalidParameter,
+    TxFailure,
+    Unknown(u8),
+}
+
+impl AtStatus {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0x00 => AtStatus::Ok,
+            0x01 => AtStatus::Error,
+            0x02 => AtStatus::InvalidCommand,
+            0x03 => AtStatus::InvalidParameter,
+            0x04 => AtStatus::TxFailure,
+            other => AtStatus::Unknown(other),
+        }
+    }
+}
+
+impl std::fmt::Display for AtStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            AtStatus::Ok => write!(f, "OK"),
+            AtStatus::Error => write!(f, "Error"),
+            AtStatus::InvalidCommand => write!(f, "Invalid command"),
+            AtStatus::InvalidParameter => write!(f, "Invalid parameter"),
+            AtStatus::TxFailure => write!(f, "Transmission failure"),
+            AtStatus::Unknown(code) => write!(f, "Unknown status 0x{:02x}", code),
+        }
+    }
+}
+
 /********************* Remote AtCommand Frame ****************************************/
 pub struct RemoteCommandOptions {
     pub apply_changes: bool,
@@ -395,6 +1091,10 @@ impl RecieveApiFrame for RemoteAtCommandResponse {
         FrameId::RemoteAtCommandResponse
     }
 
+    fn request_frame_id(&self) -> Option<u8> {
+        Some(self.frame_id)
+    }
+
     fn recieve(mut ser: Box<dyn SerialPort>) -> Result<Self> {
         let mut buffer = BytesMut::with_capacity(1024);
         let mut mini_buf: [u8; 1] = [0];
@@ -409,8 +1109,23 @@ impl RecieveApiFrame for RemoteAtCommandResponse {
             buffer.put_u8(mini_buf[0]);
         }
 
+        Self::from_frame(buffer)
+    }
+
+    fn payload(&self) -> Result<BytesMut> {
+        match &self.payload {
+            Some(p) => Ok(p.clone()),
+            None => Err(Error::FrameError("Empty payload".to_string())),
+        }
+    }
+}
+impl RemoteAtCommandResponse {
+    pub fn from_frame(buffer: BytesMut) -> Result<Self> {
+        if buffer.len() < 19 {
+            return Err(Error::FrameError("No frame detected".to_string()));
+        }
         let mut cmd_data = None;
-        if buffer.len() > 18 {
+        if buffer.len() > 19 {
             cmd_data = Some(BytesMut::from(&buffer[18..buffer.len() - 1]));
         }
         let mut at_cmd: Vec<u8> = Vec::new();
@@ -428,13 +1143,19 @@ impl RecieveApiFrame for RemoteAtCommandResponse {
         })
     }
 
-    fn payload(&self) -> Result<BytesMut> {
-        match &self.payload {
-            Some(p) => Ok(p.clone()),
-            None => Err(Error::FrameError("Empty payload".to_string())),
-        }
+    pub fn frame_id(&self) -> u8 {
+        self.frame_id
+    }
+
+    pub fn command_status(&self) -> u8 {
+        self.command_status
+    }
+
+    pub fn status(&self) -> AtStatus {
+        AtStatus::from_code(self.command_status)
     }
 }
+
 /********************* AtCommand Frame ****************************************/
 
 pub struct AtCommandFrame<'a>(pub &'a str, pub Option<&'a [u8]>);
@@ -464,6 +1185,36 @@ impl TransmitApiFrame for AtCommandFrame<'_> {
     }
 }
 
+/********************* AtCommand Queue Frame **********************************/
+
+/// Like `AtCommandFrame`, but the new value only takes effect with AC
+pub struct AtCommandQueueFrame<'a>(pub &'a str, pub Option<&'a [u8]>);
+impl TransmitApiFrame for AtCommandQueueFrame<'_> {
+    fn id(&self) -> FrameId {
+        FrameId::AtCommandQueue
+    }
+
+    fn gen(&self) -> Result<BytesMut> {
+        let mut packet = BytesMut::with_capacity(9);
+        let frame_id: u8 = self.gen_frame_id();
+        packet.put_u8(DELIM);
+        packet.put_u16(0); // length 0 just a placeholder
+        packet.put_u8(self.id().id());
+        packet.put_u8(frame_id);
+        packet.put(self.0.as_bytes());
+        if let Some(param) = self.1 {
+            packet.put(&param[..])
+        }
+
+        let packet_len = (packet.len() - 3) as u16;
+        packet[1] = (packet_len >> 8) as u8;
+        packet[2] = (packet_len & 0xff) as u8;
+        let chksum = self.calc_checksum(&packet[..])?;
+        packet.put_u8(chksum);
+        Ok(packet)
+    }
+}
+
 /******************* AtCommand Response Frame *******************/
 pub struct AtCommandResponse {
     pub frame_id: u8,
@@ -496,6 +1247,10 @@ impl RecieveApiFrame for AtCommandResponse {
         FrameId::AtCommandResponse
     }
 
+    fn request_frame_id(&self) -> Option<u8> {
+        Some(self.frame_id)
+    }
+
     fn recieve(mut ser: Box<dyn SerialPort>) -> Result<Self> {
         let mut buffer = BytesMut::with_capacity(256);
         let mut mini_buf: [u8; 1] = [0];
@@ -509,12 +1264,25 @@ impl RecieveApiFrame for AtCommandResponse {
             }
             buffer.put_u8(mini_buf[0]);
         }
+        Self::from_frame(buffer)
+    }
+
+    fn payload(&self) -> Result<BytesMut> {
+        match &self.payload {
+            Some(p) => Ok(p.clone()),
+            None => Err(Error::FrameError("Emtpy payload".to_string())),
+        }
+    }
+}
+
+impl AtCommandResponse {
+    pub fn from_frame(buffer: BytesMut) -> Result<Self> {
         let mut cmd_data = None;
         if buffer.len() > 9 {
             cmd_data = Some(BytesMut::from(&buffer[8..buffer.len() - 1]));
         }
 
-        if buffer.len() == 0 {
+        if buffer.len() < 9 {
             return Err(Error::FrameError("No frame detected".to_string()));
         }
         let mut at_cmd: Vec<u8> = Vec::new();
@@ -529,10 +1297,163 @@ impl RecieveApiFrame for AtCommandResponse {
         })
     }
 
-    fn payload(&self) -> Result<BytesMut> {
-        match &self.payload {
-            Some(p) => Ok(p.clone()),
-            None => Err(Error::FrameError("Emtpy payload".to_string())),
+    pub fn status(&self) -> AtStatus {
+        AtStatus::from_code(self.command_status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static ADDR: u64 = 0x0013a20040a1b2c3;
+
+    /// complete frame around `body`, which starts with the frame type
+    fn frame(body: &[u8]) -> BytesMut {
+        let mut frame = BytesMut::with_capacity(body.len() + 4);
+        frame.put_u8(DELIM);
+        frame.put_u16(body.len() as u16);
+        frame.put(body);
+        frame.put_u8(0xff - body.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)));
+        frame
+    }
+
+    #[test]
+    fn route_information_hop() {
+        let mut body = vec![0x8d, 0x12, 0x2a, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x00];
+        for addr in [ADDR, 1, 2, 3] {
+            body.extend_from_slice(&u64::to_be_bytes(addr));
         }
+        let frame = decode_frame(frame(&body)).unwrap();
+        let hop = frame.downcast_ref::<RouteInformation>().unwrap();
+
+        assert_eq!(hop.event, RouteEvent::TraceRoute);
+        assert_eq!(hop.timestamp, 0x00010203);
+        assert_eq!(hop.ack_timeout_count, 4);
+        assert_eq!(hop.tx_blocked_count, 5);
+        assert_eq!(hop.dest_addr, ADDR);
+        assert_eq!(hop.source_addr, 1);
+        assert_eq!(hop.responder_addr, 2);
+        assert_eq!(hop.receiver_addr, 3);
+    }
+
+    #[test]
+    fn route_information_too_short() {
+        assert!(RouteInformation::from_frame(frame(&[0x8d, 0x11, 0x2a])).is_err());
+    }
+
+    #[test]
+    fn receive_packet_fields() {
+        let mut body = vec![0x90];
+        body.extend_from_slice(&ADDR.to_be_bytes());
+        body.extend_from_slice(&[0xff, 0xfe, 0xc1]);
+        body.extend_from_slice(b"hello");
+        let frame = decode_frame(frame(&body)).unwrap();
+        let packet = frame.downcast_ref::<ReceivePacket>().unwrap();
+
+        assert_eq!(packet.source_addr, ADDR);
+        assert_eq!(&packet.data[..], b"hello");
+        assert_eq!(
+            packet.options,
+            ReceiveOptions {
+                acknowledged: true,
+                broadcast: false,
+                encrypted: false,
+                mode: Some(MessagingMode::DigiMesh),
+            }
+        );
+    }
+
+    #[test]
+    fn receive_packet_without_data() {
+        let mut body = vec![0x90];
+        body.extend_from_slice(&ADDR.to_be_bytes());
+        body.extend_from_slice(&[0xff, 0xfe, 0x22]);
+        let packet = ReceivePacket::from_frame(frame(&body)).unwrap();
+
+        assert!(packet.data.is_empty());
+        assert!(packet.options.broadcast);
+        assert!(packet.options.encrypted);
+        assert_eq!(packet.options.mode, None);
+        assert!(ReceivePacket::from_frame(frame(&body[..8])).is_err());
+    }
+
+    #[test]
+    fn transmit_status_fields() {
+        let frame = decode_frame(frame(&[0x8b, 0x2f, 0xff, 0xfe, 0x03, 0x21, 0x02])).unwrap();
+        let status = frame.downcast_ref::<TransmitStatus>().unwrap();
+
+        assert_eq!(status.frame_id(), 0x2f);
+        assert_eq!(status.retry_count(), 3);
+        assert!(!status.delivered());
+        assert_eq!(status.delivery_status(), DeliveryStatus::NetworkAckFailure);
+        assert_eq!(status.discovery_status(), DiscoveryStatus::Route);
+    }
+
+    #[test]
+    fn delivery_status_codes() {
+        assert_eq!(DeliveryStatus::from_code(0x00), DeliveryStatus::Success);
+        assert_eq!(DeliveryStatus::from_code(0x31), DeliveryStatus::ResourceError);
+        assert_eq!(DeliveryStatus::from_code(0x74), DeliveryStatus::PayloadTooLarge);
+        assert_eq!(DeliveryStatus::from_code(0x99), DeliveryStatus::Unknown(0x99));
+        assert!(TransmitStatus::from_frame(frame(&[0x8b, 0x01, 0xff, 0xfe])).is_err());
+    }
+
+    #[test]
+    fn explicit_rx_indicator_fields() {
+        let mut body = vec![0x91];
+        body.extend_from_slice(&ADDR.to_be_bytes());
+        body.extend_from_slice(&[0xff, 0xfe, 0xe8, 0xe6, 0x00, 0x11, 0xc1, 0x05, 0x01]);
+        body.extend_from_slice(b"data");
+        let decoded = decode_frame(frame(&body)).unwrap();
+        let packet = decoded.downcast_ref::<ExplicitRxIndicator>().unwrap();
+
+        assert_eq!(packet.source_addr, ADDR);
+        assert_eq!(packet.src_endpoint, 0xe8);
+        assert_eq!(packet.dest_endpoint, 0xe6);
+        assert_eq!(packet.cluster_id, 0x0011);
+        assert_eq!(packet.profile_id, 0xc105);
+        assert!(packet.options.acknowledged);
+        assert_eq!(&packet.data[..], b"data");
+        assert!(ExplicitRxIndicator::from_frame(frame(&body[..14])).is_err());
+    }
+
+    #[test]
+    fn io_sample_with_digital_analog_and_supply() {
+        let data = [
+            0x01, 0x00, 0x13, 0x83, 0x01, 0x11, 0x02, 0x00, 0x81, 0x00, 0x0c, 0xe4,
+        ];
+        let sample = IoSample::parse(&data).unwrap();
+
+        assert_eq!(sample.digital, 0x0011); // DIO8 is not sampled
+        assert_eq!(sample.digital_state(0), Some(true));
+        assert_eq!(sample.digital_state(1), Some(false));
+        assert_eq!(sample.digital_state(2), None);
+        assert_eq!(sample.analog, vec![(0, 0x200), (1, 0x100)]);
+        assert_eq!(sample.analog_millivolts(0, 1200), Some(600));
+        assert_eq!(sample.analog_value(2), None);
+        assert_eq!(sample.supply_millivolts(), Some(3300));
+    }
+
+    #[test]
+    fn io_sample_analog_only_and_truncated() {
+        let sample = IoSample::parse(&[0x01, 0x00, 0x00, 0x04, 0x01, 0xff]).unwrap();
+        assert_eq!(sample.digital, 0);
+        assert_eq!(sample.analog, vec![(2, 0x1ff)]);
+        assert_eq!(sample.supply_voltage, None);
+
+        assert!(IoSample::parse(&[0x01, 0x00, 0x01, 0x01, 0x00]).is_err());
+    }
+
+    #[test]
+    fn io_data_sample_frame() {
+        let mut body = vec![0x92];
+        body.extend_from_slice(&ADDR.to_be_bytes());
+        body.extend_from_slice(&[0xff, 0xfe, 0x01, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01]);
+        let decoded = decode_frame(frame(&body)).unwrap();
+        let sample = decoded.downcast_ref::<IoDataSample>().unwrap();
+
+        assert_eq!(sample.source_addr, ADDR);
+        assert_eq!(sample.sample.digital_state(0), Some(true));
     }
 }
\ No newline at end of file
@@ -0,0 +1,147 @@
+//!
+//! IEEE registry CSV parsing, shared between the build script and the OUI module
+//!
+//!
+
+/// Prefix length in bits of each IEEE registry, longest first
+pub static REGISTRIES: [(&str, u8); 3] = [("MA-S", 36), ("MA-M", 28), ("MA-L", 24)];
+
+/// One assignment: prefix length in bits, prefix value and organization name
+pub type Assignment = (u8, u64, String);
+
+/// Prefixes (length in bits, prefix value, organization index) and organization names
+pub type PrefixTable = (Vec<(u8, u64, u32)>, Vec<String>);
+
+/// Extract the MA-L, MA-M and MA-S assignments from the registry CSV
+/// (Registry,Assignment,Organization Name,Organization Address)
+pub fn parse_registry(contents: &str) -> Vec<Assignment> {
+    let mut assignments = Vec::new();
+
+    for record in parse_csv(contents) {
+        if record.len() < 3 {
+            continue;
+        }
+        let bits = match REGISTRIES.iter().find(|(name, _)| *name == record[0]) {
+            Some((_, bits)) => *bits,
+            None => continue, // header or unsupported registry
+        };
+        let assignment = record[1].trim();
+        if assignment.len() * 4 != bits as usize {
+            continue;
+        }
+        if let Ok(prefix) = u64::from_str_radix(assignment, 16) {
+            assignments.push((bits, prefix, record[2].trim().to_string()));
+        }
+    }
+
+    assignments
+}
+
+/// Sort the assignments by prefix length then prefix and store each organization
+/// name once, referenced by index. Duplicate prefixes keep their first entry.
+pub fn build_table(mut assignments: Vec<Assignment>) -> PrefixTable {
+    assignments.sort_by_key(|(bits, prefix, _)| (*bits, *prefix));
+    assignments.dedup_by_key(|(bits, prefix, _)| (*bits, *prefix));
+
+    let mut orgs: Vec<String> = Vec::new();
+    let mut index: std::collections::HashMap<String, u32> = std::collections::HashMap::new();
+    let mut prefixes = Vec::with_capacity(assignments.len());
+    for (bits, prefix, org) in assignments {
+        let idx = *index.entry(org.clone()).or_insert_with(|| {
+            orgs.push(org);
+            (orgs.len() - 1) as u32
+        });
+        prefixes.push((bits, prefix, idx));
+    }
+
+    (prefixes, orgs)
+}
+
+/// Split CSV text into records, honouring quoted fields that contain commas,
+/// doubled quotes or line breaks.
+fn parse_csv(contents: &str) -> Vec<Vec<String>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = contents.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    field.push('"');
+                    chars.next();
+                } else {
+                    quoted = false;
+                }
+            }
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => record.push(std::mem::take(&mut field)),
+            '\r' if !quoted => {}
+            '\n' if !quoted => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            _ => field.push(c),
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_quoted_fields() {
+        let records =
+            parse_csv("a,\"b, c\",\"say \"\"hi\"\"\"\r\n\"multi\nline\",,last\nno newline");
+        assert_eq!(
+            records,
+            vec![
+                vec!["a", "b, c", "say \"hi\""],
+                vec!["multi\nline", "", "last"],
+                vec!["no newline"],
+            ]
+        );
+        assert!(parse_csv("").is_empty());
+    }
+
+    #[test]
+    fn registry_keeps_known_assignments() {
+        let assignments = parse_registry(
+            "Registry,Assignment,Organization Name,Organization Address\n\
+             MA-L,0013A2,Digi International Inc,\"9350 Excelsior Blvd, Hopkins\"\n\
+             MA-M,70B3D5F,\"Acme, Inc.\",Somewhere\n\
+             MA-S,70B3D5F2A,Tiny Co,\n\
+             MA-L,12345,Wrong Length,\n\
+             CID,001234,Company ID,\n",
+        );
+        assert_eq!(
+            assignments,
+            vec![
+                (24, 0x0013a2, "Digi International Inc".to_string()),
+                (28, 0x70b3d5f, "Acme, Inc.".to_string()),
+                (36, 0x70b3d5f2a, "Tiny Co".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn table_is_sorted_and_shares_names() {
+        let (prefixes, orgs) = build_table(vec![
+            (28, 0x2, "B".to_string()),
+            (24, 0x3, "A".to_string()),
+            (24, 0x1, "B".to_string()),
+            (24, 0x1, "C".to_string()),
+        ]);
+        assert_eq!(prefixes, vec![(24, 0x1, 0), (24, 0x3, 1), (28, 0x2, 0)]);
+        assert_eq!(orgs, vec!["B", "A"]);
+    }
+}
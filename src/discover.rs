@@ -1,7 +1,13 @@
-use crate::api::{self, AtCommand, AtCommands, RecieveApiFrame, TransmitApiFrame};
+use crate::api::{self, AtCommand, AtCommands};
+use crate::inventory::NodeInventory;
+use crate::sleep;
+use crate::topology::Neighbor;
+use crate::transparent;
 use bytes::{BufMut, BytesMut};
 use serialport::*;
+use std::collections::VecDeque;
 use std::convert::TryFrom;
+use std::sync::mpsc;
 use std::thread;
 use std::time::{Duration,Instant};
 
@@ -12,7 +18,12 @@ pub enum Error {
     DecodeError(std::str::Utf8Error),
     ApiError(api::Error),
     InvalidMode(String),
+    NoCommandMode, // no answer to the command sequence
     DiscoveryError,
+    DeliveryError(api::DeliveryStatus),
+    CommandError(String, api::AtStatus),
+    UnknownNode(String),
+    Timeout,
 }
 
 impl From<serialport::Error> for Error {
@@ -46,24 +57,140 @@ impl std::fmt::Display for Error {
             Error::IOError(ref err) => write!(f, "{}", err),
             Error::DecodeError(ref err) => write!(f, "{}", err),
             Error::InvalidMode(ref err) => write!(f, "{}", err),
+            Error::NoCommandMode => write!(f, "Module did not enter command mode"),
             Error::ApiError(ref err) => write!(f, "{}", err),
             Error::DiscoveryError => write!(f, "Could not complete discovery mode"),
+            Error::DeliveryError(ref status) => write!(f, "Delivery failed: {}", status),
+            Error::CommandError(ref atcmd, status) => {
+                write!(f, "AT command {} failed: {}", atcmd, status)
+            }
+            Error::UnknownNode(ref node_id) => write!(f, "No node named {}", node_id),
+            Error::Timeout => write!(f, "Timed out waiting for a response"),
         }
     }
 }
 
 impl std::error::Error for Error {}
 
+/// unsolicited frames of each type kept until read
+static RX_QUEUE_LEN: usize = 64;
+
+/// time given to the module to report the outcome of a transmission
+static TX_STATUS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// time given to a remote node to answer an AT command
+static REMOTE_AT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// remote AT commands sent again after a transmission failure or no answer
+static REMOTE_AT_RETRIES: u32 = 2;
+
+/// data received by the application of a node probed with `trace_route`
+pub static TRACE_PAYLOAD: &[u8] = b"TRACE";
+
 // pub type Result<T> = std::result::Result<T, Error>;
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Device type field of ND and FN records
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceRole {
+    Coordinator,
+    Router,
+    EndDevice,
+    Unknown,
+}
+
+impl DeviceRole {
+    pub(crate) fn from_device_type(device_type: u8) -> Self {
+        match device_type {
+            0 => DeviceRole::Coordinator,
+            1 => DeviceRole::Router,
+            2 => DeviceRole::EndDevice,
+            _ => DeviceRole::Unknown,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DeviceRole::Coordinator => "coordinator",
+            DeviceRole::Router => "router",
+            DeviceRole::EndDevice => "end_device",
+            DeviceRole::Unknown => "unknown",
+        }
+    }
+}
+
+/// Unsolicited frames of one type, kept until read and copied to subscribers
+struct FrameQueue<T> {
+    queue: VecDeque<T>,
+    subscribers: Vec<mpsc::Sender<T>>,
+}
+
+impl<T: Clone> FrameQueue<T> {
+    fn new() -> Self {
+        Self {
+            queue: VecDeque::new(),
+            subscribers: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, item: T) {
+        self.subscribers
+            .retain(|subscriber| subscriber.send(item.clone()).is_ok());
+        if self.queue.len() >= RX_QUEUE_LEN {
+            self.queue.pop_front();
+        }
+        self.queue.push_back(item);
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        self.queue.pop_front()
+    }
+
+    fn subscribe(&mut self) -> mpsc::Receiver<T> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.push(tx);
+        rx
+    }
+}
+
+/// Module an AT command is run on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NodeTarget {
+    Local,
+    Remote(u64),
+}
+
+/// Remote node given by its 64-bit address or its node identifier (NI)
+#[derive(Debug, Clone, PartialEq)]
+pub enum RemoteNode {
+    Addr(u64),
+    NodeId(String),
+}
+
+impl From<u64> for RemoteNode {
+    fn from(addr: u64) -> Self {
+        RemoteNode::Addr(addr)
+    }
+}
+
+impl From<&str> for RemoteNode {
+    fn from(node_id: &str) -> Self {
+        RemoteNode::NodeId(node_id.to_string())
+    }
+}
+
 #[derive(Debug)]
 pub struct RemoteDigiMeshDevice {
     pub addr_64bit: u64,
     pub node_id: String,
+    pub role: DeviceRole,
+    pub vendor: Option<String>,
     pub firmware_version: Option<u16>,
     pub hardware_version: Option<u16>,
+    pub inventory: Option<NodeInventory>,
     pub durations: Vec<(Instant, Instant)>, // Nouveau champ pour les durées de détection
+    pub sleep_cycle: Option<Duration>,      // learned or configured, None when it stays awake
+    pub sleep_learned: bool,                // sleep_cycle was read from the node
 }
 
 pub struct DigiMeshDevice {
@@ -72,9 +199,18 @@ pub struct DigiMeshDevice {
     pub firmware_version: Option<u16>,
     pub hardware_version: Option<u16>,
     pub nodes: Option<Vec<RemoteDigiMeshDevice>>,
+    pub auto_reinit: bool, // re-read the module identity after it reports a reset
+    pub learn_sleep: bool, // read the sleep cycle of the nodes found by scheduled scans
     serial: Box<dyn SerialPort>,
     rx_buf: BytesMut,
     tx_buf: BytesMut,
+    received: FrameQueue<api::ReceivePacket>,
+    explicit_received: FrameQueue<api::ExplicitRxIndicator>,
+    modem_status: FrameQueue<api::ModemStatus>,
+    io_samples: FrameQueue<api::IoDataSample>,
+    reset_pending: bool,
+    pub guard_time: Duration, // silence around the command sequence (GT)
+    pub command_char: u8,     // character of the command sequence (CC)
 }
 
 impl std::fmt::Debug for DigiMeshDevice {
@@ -90,6 +226,32 @@ impl std::fmt::Debug for DigiMeshDevice {
 
 impl DigiMeshDevice {
     pub fn new<'a>(port: &'a str, baud: u32) -> Result<Self> {
+        let mut device = Self::open(port, baud)?;
+        device.initialize()?;
+
+        Ok(device)
+    }
+
+    /// Open a module like `new`, switching it to API mode first when it is
+    /// still in transparent mode, as it comes from the factory
+    pub fn onboard<'a>(port: &'a str, baud: u32) -> Result<Self> {
+        let mut device = Self::open(port, baud)?;
+        // command mode answers in both modes, unlike API frames which a module
+        // in transparent mode would send over the air
+        match transparent::read_api_mode(&mut device) {
+            // a module in API mode may ignore the command sequence
+            Ok(transparent::ApiMode::Api) | Err(Error::NoCommandMode) => {}
+            Ok(_) => transparent::enable_api_mode(&mut device, transparent::ApiMode::Api)?,
+            Err(err) => return Err(err),
+        }
+        device.initialize()?;
+
+        Ok(device)
+    }
+
+    /// Open the serial port without talking to the module, for modules that
+    /// are not in API mode yet
+    pub fn open<'a>(port: &'a str, baud: u32) -> Result<Self> {
         let settings = SerialPortSettings {
             baud_rate: baud,
             data_bits: DataBits::Eight,
@@ -99,7 +261,7 @@ impl DigiMeshDevice {
             timeout: Duration::from_millis(20000),
         };
 
-        let mut device = Self {
+        let device = Self {
             serial: serialport::open_with_settings(port, &settings)?,
             rx_buf: BytesMut::with_capacity(128),
             tx_buf: BytesMut::with_capacity(128),
@@ -108,20 +270,45 @@ impl DigiMeshDevice {
             firmware_version: None,
             hardware_version: None,
             nodes: None,
+            auto_reinit: true,
+            learn_sleep: false,
+            received: FrameQueue::new(),
+            explicit_received: FrameQueue::new(),
+            modem_status: FrameQueue::new(),
+            io_samples: FrameQueue::new(),
+            reset_pending: false,
+            guard_time: Duration::from_millis(1000),
+            command_char: b'+',
         };
-        let addr = device.get_64bit_addr()?;
-        let node_id = device.get_node_id()?;
-        let hw_version = device.get_hardware_version()?;
-        let fw_version = device.get_firmware_version()?;
-
-        device.addr_64bit = Some(addr);
-        device.node_id = Some(node_id);
-        device.hardware_version = Some(hw_version);
-        device.firmware_version = Some(fw_version);
 
         Ok(device)
     }
 
+    /// read the identity of the local module
+    fn initialize(&mut self) -> Result<()> {
+        let addr = self.get_64bit_addr()?;
+        let node_id = self.get_node_id()?;
+        let hw_version = self.get_hardware_version()?;
+        let fw_version = self.get_firmware_version()?;
+
+        self.addr_64bit = Some(addr);
+        self.node_id = Some(node_id);
+        self.hardware_version = Some(hw_version);
+        self.firmware_version = Some(fw_version);
+
+        Ok(())
+    }
+
+    /// Forget the cached identity of the local module and read it again
+    pub fn reinitialize(&mut self) -> Result<()> {
+        self.reset_pending = false;
+        self.addr_64bit = None;
+        self.node_id = None;
+        self.hardware_version = None;
+        self.firmware_version = None;
+        self.initialize()
+    }
+
     pub fn get_firmware_version(&mut self) -> Result<u16> {
         if let None = self.firmware_version {
             let fw = self.send_frame(api::AtCommandFrame("VR", None))?;
@@ -136,6 +323,20 @@ impl DigiMeshDevice {
         Ok(self.firmware_version.unwrap())
     }
 
+    /// Role of the local module, as ND reports it for remote nodes: indirect
+    /// messaging coordinators (CE=1) are coordinators, non-routing (CE=2) and
+    /// sleeping modules are end devices, the others routers
+    pub fn get_role(&mut self) -> Result<DeviceRole> {
+        let ce = self.execute(NodeTarget::Local, "CE", None)?.unwrap_or_else(BytesMut::new);
+        let sm = self.execute(NodeTarget::Local, "SM", None)?.unwrap_or_else(BytesMut::new);
+        Ok(match (ce.last(), sm.last()) {
+            (Some(1), _) => DeviceRole::Coordinator,
+            (Some(2), _) => DeviceRole::EndDevice,
+            (_, Some(sm)) if *sm != 0 && *sm != 7 => DeviceRole::EndDevice,
+            _ => DeviceRole::Router,
+        })
+    }
+
     pub fn get_hardware_version(&mut self) -> Result<u16> {
         if let None = self.hardware_version {
             let fw = self.send_frame(api::AtCommandFrame("HV", None))?;
@@ -194,33 +395,561 @@ impl DigiMeshDevice {
         Ok(self.serial.write(data)?)
     }
 
-    pub fn discover_nodes(&mut self, timeout: Option<Duration>) -> Result<()> {
-        let discover_cmd = api::AtCommandFrame("ND", None).gen()?;
-        self.serial.write(&discover_cmd[..])?;
+    /// Re-read the module identity when it reported a reset. Called before a
+    /// new request is sent, never while another one waits for its response.
+    pub(crate) fn reinitialize_if_reset(&mut self) -> Result<()> {
+        if self.reset_pending && self.auto_reinit {
+            self.reinitialize()?;
+        }
+        Ok(())
+    }
 
-        let old_timeout = self.serial.timeout();
-        if let Some(t) = timeout {
-            self.serial.set_timeout(t)?;
-        } else {
-            self.serial.set_timeout(Duration::from_secs(15))?;
+    /// write a frame to the module and return the frame id it was sent with
+    pub(crate) fn write_frame<T: api::TransmitApiFrame>(&mut self, frame: &T) -> Result<u8> {
+        let packet = frame.gen()?;
+        self.serial.write_all(&packet[..])?;
+        Ok(packet[4])
+    }
+
+    /// read the next frame that answers a request, or `None` once the deadline
+    /// has passed. Unsolicited frames met on the way are dispatched.
+    pub(crate) fn next_frame(
+        &mut self,
+        deadline: Instant,
+    ) -> Result<Option<Box<dyn api::RecieveApiFrame>>> {
+        while let Some(frame) = self.read_any_frame(deadline)? {
+            if let Some(frame) = self.dispatch(frame) {
+                return Ok(Some(frame));
+            }
         }
+        Ok(None)
+    }
 
-        let mut remote_devices: Vec<RemoteDigiMeshDevice> = Vec::new();
+    /// hand unsolicited frames to their queue and subscribers, give back the others
+    fn dispatch(
+        &mut self,
+        frame: Box<dyn api::RecieveApiFrame>,
+    ) -> Option<Box<dyn api::RecieveApiFrame>> {
+        let frame = match frame.downcast::<api::ReceivePacket>() {
+            Ok(packet) => {
+                self.received.push(*packet);
+                return None;
+            }
+            Err(frame) => frame,
+        };
+        let frame = match frame.downcast::<api::ExplicitRxIndicator>() {
+            Ok(packet) => {
+                self.explicit_received.push(*packet);
+                return None;
+            }
+            Err(frame) => frame,
+        };
+        let frame = match frame.downcast::<api::IoDataSample>() {
+            Ok(sample) => {
+                self.io_samples.push(*sample);
+                return None;
+            }
+            Err(frame) => frame,
+        };
+        match frame.downcast::<api::ModemStatus>() {
+            Ok(status) => {
+                if status.status.is_reset() {
+                    self.reset_pending = true;
+                }
+                self.modem_status.push(*status);
+                None
+            }
+            Err(frame) => Some(frame),
+        }
+    }
+
+    /// wait up to `timeout` for an unsolicited frame of the given queue
+    fn recv_queued<T: Clone>(
+        &mut self,
+        timeout: Duration,
+        queue: fn(&mut Self) -> &mut FrameQueue<T>,
+    ) -> Result<Option<T>> {
+        let deadline = Instant::now() + timeout;
         loop {
-            match api::AtCommandResponse::recieve(self.serial.try_clone()?) {
-                Ok(resp) => {
-                    if let Some(device) = parse_remote_device(&resp) {
-                        remote_devices.push(device);
+            if let Some(item) = queue(self).pop() {
+                return Ok(Some(item));
+            }
+            match self.read_any_frame(deadline)? {
+                // nobody is waiting on a response frame here
+                Some(frame) => {
+                    self.dispatch(frame);
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// read the next decodable frame, or `None` once the deadline has passed
+    fn read_any_frame(
+        &mut self,
+        deadline: Instant,
+    ) -> Result<Option<Box<dyn api::RecieveApiFrame>>> {
+        let old_timeout = self.serial.timeout();
+        let mut result = Ok(None);
+
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            self.serial.set_timeout(deadline - now)?;
+
+            match api::read_frame(&mut *self.serial) {
+                Ok(frame) => match api::decode_frame(frame) {
+                    Ok(frame) => {
+                        result = Ok(Some(frame));
+                        break;
                     }
+                    // unsupported or malformed frames are skipped
+                    Err(api::Error::FrameError(_)) => continue,
+                    Err(err) => {
+                        result = Err(Error::ApiError(err));
+                        break;
+                    }
+                },
+                Err(api::Error::FrameError(_)) => continue,
+                Err(api::Error::IOError(ref err)) if err.kind() == std::io::ErrorKind::TimedOut => {
+                    break;
+                }
+                Err(err) => {
+                    result = Err(Error::ApiError(err));
+                    break;
                 }
-                Err(_) => break,
             }
         }
+
         self.serial.set_timeout(old_timeout)?;
+        result
+    }
+
+    /// Send `payload` to `addr` and wait for its transmit status. Returns the
+    /// number of retries the transmission needed.
+    pub fn send_data(
+        &mut self,
+        addr: u64,
+        payload: &[u8],
+        opts: Option<&api::TransmitRequestOptions>,
+    ) -> Result<u8> {
+        self.transmit(&api::TransmitRequestFrame {
+            dest_addr: addr,
+            broadcast_radius: 0,
+            options: opts,
+            payload,
+        })
+    }
+
+    /// Send an explicitly addressed frame and wait for its transmit status.
+    /// Returns the number of retries the transmission needed.
+    pub fn send_explicit(&mut self, frame: &api::ExplicitAddressingFrame) -> Result<u8> {
+        self.transmit(frame)
+    }
+
+    fn transmit<T: api::TransmitApiFrame>(&mut self, frame: &T) -> Result<u8> {
+        self.reinitialize_if_reset()?;
+        let frame_id = self.write_frame(frame)?;
+        let deadline = Instant::now() + TX_STATUS_TIMEOUT;
+
+        while let Some(frame) = self.next_frame(deadline)? {
+            if let Some(status) = frame.downcast_ref::<api::TransmitStatus>() {
+                if status.frame_id() != frame_id {
+                    continue;
+                }
+                return match status.delivery_status() {
+                    api::DeliveryStatus::Success => Ok(status.retry_count()),
+                    failure => Err(Error::DeliveryError(failure)),
+                };
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    /// Send `payload` to every node of the network
+    pub fn send_broadcast(&mut self, payload: &[u8]) -> Result<u8> {
+        self.send_data(api::BROADCAST_ADDR, payload, None)
+    }
+
+    /// Wait up to `timeout` for data sent by a remote node. Packets received while
+    /// waiting on other responses are queued and returned first.
+    pub fn recv_data(&mut self, timeout: Duration) -> Result<Option<api::ReceivePacket>> {
+        self.recv_queued(timeout, |device| &mut device.received)
+    }
+
+    /// Get a copy of every data packet received from now on. Packets are
+    /// delivered while the device reads from the serial port, see `poll`.
+    pub fn subscribe_data(&mut self) -> mpsc::Receiver<api::ReceivePacket> {
+        self.received.subscribe()
+    }
+
+    /// Wait up to `timeout` for data received in explicit mode (AO=1)
+    pub fn recv_explicit(&mut self, timeout: Duration) -> Result<Option<api::ExplicitRxIndicator>> {
+        self.recv_queued(timeout, |device| &mut device.explicit_received)
+    }
+
+    /// Get a copy of every explicit rx indicator received from now on
+    pub fn subscribe_explicit(&mut self) -> mpsc::Receiver<api::ExplicitRxIndicator> {
+        self.explicit_received.subscribe()
+    }
+
+    /// Wait up to `timeout` for IO samples sent by a remote node (IR/IC)
+    pub fn recv_io_sample(&mut self, timeout: Duration) -> Result<Option<api::IoDataSample>> {
+        self.recv_queued(timeout, |device| &mut device.io_samples)
+    }
+
+    /// Get a copy of every IO sample received from now on
+    pub fn subscribe_io_samples(&mut self) -> mpsc::Receiver<api::IoDataSample> {
+        self.io_samples.subscribe()
+    }
+
+    /// Read and dispatch incoming frames for `duration`
+    pub fn poll(&mut self, duration: Duration) -> Result<()> {
+        let deadline = Instant::now() + duration;
+        while let Some(frame) = self.read_any_frame(deadline)? {
+            self.dispatch(frame);
+        }
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for the next modem status reported by the module
+    pub fn recv_modem_status(&mut self, timeout: Duration) -> Result<Option<api::ModemStatus>> {
+        self.recv_queued(timeout, |device| &mut device.modem_status)
+    }
+
+    /// Get a copy of every modem status reported from now on
+    pub fn subscribe_modem_status(&mut self) -> mpsc::Receiver<api::ModemStatus> {
+        self.modem_status.subscribe()
+    }
+
+    /// send a local AT command and wait for the response carrying the same frame id
+    pub(crate) fn at_command(
+        &mut self,
+        atcmd: &str,
+        param: Option<&[u8]>,
+        timeout: Duration,
+    ) -> Result<api::AtCommandResponse> {
+        self.reinitialize_if_reset()?;
+        let frame_id = self.write_frame(&api::AtCommandFrame(atcmd, param))?;
+        let deadline = Instant::now() + timeout;
+
+        while let Some(frame) = self.next_frame(deadline)? {
+            if let Ok(resp) = frame.downcast::<api::AtCommandResponse>() {
+                if resp.frame_id == frame_id {
+                    return Ok(*resp);
+                }
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    /// send a remote AT command and wait for the response carrying the same frame id
+    pub(crate) fn remote_at_command(
+        &mut self,
+        addr: u64,
+        atcmd: &str,
+        param: Option<&[u8]>,
+        apply_changes: bool,
+        timeout: Duration,
+    ) -> Result<api::RemoteAtCommandResponse> {
+        self.reinitialize_if_reset()?;
+        let options = api::RemoteCommandOptions { apply_changes };
+        let frame_id = self.write_frame(&api::RemoteAtCommandFrame {
+            dest_addr: addr,
+            options: &options,
+            atcmd,
+            cmd_param: param,
+        })?;
+        let deadline = Instant::now() + timeout;
+
+        while let Some(frame) = self.next_frame(deadline)? {
+            if let Ok(resp) = frame.downcast::<api::RemoteAtCommandResponse>() {
+                if resp.frame_id() == frame_id {
+                    return Ok(*resp);
+                }
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    /// Run `atcmd` on the local module or on a remote node, applying the change
+    /// right away, and return the command data of a successful response.
+    pub fn execute(
+        &mut self,
+        target: NodeTarget,
+        atcmd: &str,
+        param: Option<&[u8]>,
+    ) -> Result<Option<BytesMut>> {
+        match target {
+            NodeTarget::Local => {
+                let resp = self.at_command(atcmd, param, Duration::from_secs(1))?;
+                match resp.status() {
+                    api::AtStatus::Ok => Ok(resp.command_data),
+                    status => Err(Error::CommandError(atcmd.to_string(), status)),
+                }
+            }
+            NodeTarget::Remote(addr) => self.remote_command(addr, atcmd, param, true),
+        }
+    }
+
+    /// Send a remote AT command, sending it again when the node could not be
+    /// reached, and return the command data of a successful response
+    fn remote_command(
+        &mut self,
+        addr: u64,
+        atcmd: &str,
+        param: Option<&[u8]>,
+        apply_changes: bool,
+    ) -> Result<Option<BytesMut>> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let retry = attempt <= REMOTE_AT_RETRIES;
+            match self.remote_at_command(addr, atcmd, param, apply_changes, REMOTE_AT_TIMEOUT) {
+                Ok(resp) => match resp.status() {
+                    api::AtStatus::Ok => return Ok(resp.command_data),
+                    api::AtStatus::TxFailure if retry => continue,
+                    status => return Err(Error::CommandError(atcmd.to_string(), status)),
+                },
+                Err(Error::Timeout) if retry => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// 64-bit address of a node, looked up in the discovered nodes first and
+    /// resolved with DN otherwise
+    pub fn resolve_node(&mut self, node: &RemoteNode) -> Result<u64> {
+        let node_id = match node {
+            RemoteNode::Addr(addr) => return Ok(*addr),
+            RemoteNode::NodeId(node_id) => node_id,
+        };
+        if let Some(found) = self.nodes.iter().flatten().find(|n| n.node_id == *node_id) {
+            return Ok(found.addr_64bit);
+        }
+
+        // DN answers with MY then SH and SL of the node
+        let resp = self.at_command("DN", Some(node_id.as_bytes()), Duration::from_secs(15))?;
+        match resp.command_data {
+            Some(ref data) if resp.status() == api::AtStatus::Ok && data.len() >= 10 => Ok(
+                u64::from_be_bytes(<[u8; 8]>::try_from(&data[2..10]).unwrap()),
+            ),
+            _ => Err(Error::UnknownNode(node_id.clone())),
+        }
+    }
+
+    /// Read the parameter `atcmd` of the local module or of a remote node
+    pub fn get_param(&mut self, target: NodeTarget, atcmd: &str) -> Result<BytesMut> {
+        match target {
+            NodeTarget::Local => Ok(self
+                .execute(NodeTarget::Local, atcmd, None)?
+                .unwrap_or_else(BytesMut::new)),
+            NodeTarget::Remote(addr) => self.remote_get(addr, atcmd),
+        }
+    }
+
+    /// Set the parameter `atcmd` of the local module, or queue it on a remote
+    /// node until `remote_apply`
+    pub fn set_param(&mut self, target: NodeTarget, atcmd: &str, value: &[u8]) -> Result<()> {
+        match target {
+            NodeTarget::Local => self.execute(target, atcmd, Some(value)).map(|_| ()),
+            NodeTarget::Remote(addr) => self.remote_set(addr, atcmd, value),
+        }
+    }
+
+    /// Queue a new value of the parameter `atcmd` on the local module or on a
+    /// remote node, taking effect with the next AC
+    pub fn queue_param(&mut self, target: NodeTarget, atcmd: &str, value: &[u8]) -> Result<()> {
+        match target {
+            NodeTarget::Local => {
+                let resp = self.send_frame(api::AtCommandQueueFrame(atcmd, Some(value)))?;
+                match resp.downcast_ref::<api::AtCommandResponse>() {
+                    Some(resp) if resp.status() == api::AtStatus::Ok => Ok(()),
+                    Some(resp) => Err(Error::CommandError(atcmd.to_string(), resp.status())),
+                    None => Err(Error::Timeout),
+                }
+            }
+            NodeTarget::Remote(addr) => self.remote_set(addr, atcmd, value),
+        }
+    }
+
+    /// Read the parameter `atcmd` of a remote node
+    pub fn remote_get<N: Into<RemoteNode>>(&mut self, node: N, atcmd: &str) -> Result<BytesMut> {
+        let addr = self.resolve_node(&node.into())?;
+        Ok(self
+            .remote_command(addr, atcmd, None, false)?
+            .unwrap_or_else(BytesMut::new))
+    }
+
+    /// Queue a new value of the parameter `atcmd` on a remote node. Queued
+    /// values take effect together with `remote_apply`.
+    pub fn remote_set<N: Into<RemoteNode>>(
+        &mut self,
+        node: N,
+        atcmd: &str,
+        value: &[u8],
+    ) -> Result<()> {
+        let addr = self.resolve_node(&node.into())?;
+        self.remote_command(addr, atcmd, Some(value), false)?;
+        Ok(())
+    }
+
+    /// Apply every queued change of a remote node at once (AC)
+    pub fn remote_apply<N: Into<RemoteNode>>(&mut self, node: N) -> Result<()> {
+        let addr = self.resolve_node(&node.into())?;
+        self.remote_command(addr, "AC", None, false)?;
+        Ok(())
+    }
+
+    /// Save the configuration of a remote node to its non-volatile memory (WR)
+    pub fn remote_write<N: Into<RemoteNode>>(&mut self, node: N) -> Result<()> {
+        let addr = self.resolve_node(&node.into())?;
+        self.remote_command(addr, "WR", None, false)?;
+        Ok(())
+    }
+
+    /// maximum RF payload in bytes of a unicast transmission (NP)
+    pub fn get_max_payload(&mut self) -> Result<usize> {
+        let resp = self.at_command("NP", None, Duration::from_secs(1))?;
+        match resp.command_data {
+            Some(ref data) if !data.is_empty() => {
+                Ok(data.iter().fold(0usize, |acc, b| (acc << 8) | *b as usize))
+            }
+            _ => Err(Error::ApiError(api::Error::PayloadError(
+                "No maximum payload available".to_string(),
+            ))),
+        }
+    }
+
+    /// RSSI of the last received packet (DB), in -dBm
+    pub fn get_rssi(&mut self) -> Result<u8> {
+        let resp = self.at_command("DB", None, Duration::from_secs(1))?;
+        match resp.command_data {
+            Some(ref data) if !data.is_empty() => Ok(data[data.len() - 1]),
+            _ => Err(Error::ApiError(api::Error::PayloadError(
+                "No RSSI available".to_string(),
+            ))),
+        }
+    }
+
+    /// Send a probe to `addr` with the trace route option set and collect the
+    /// Route Information Packet reported by every hop along the path. The
+    /// probe is delivered to the application of the node as a `TRACE_PAYLOAD`
+    /// data packet. Fails when the probe could not be delivered.
+    pub fn trace_route(&mut self, addr: u64, timeout: Duration) -> Result<Vec<api::RouteInformation>> {
+        let options = api::TransmitRequestOptions {
+            disable_ack: false,
+            disable_route_discovery: false,
+            enable_unicast_nack: false,
+            enable_unicast_trace_route: true,
+            mode: api::MessagingMode::DigiMesh,
+        };
+        self.reinitialize_if_reset()?;
+        let frame_id = self.write_frame(&api::TransmitRequestFrame {
+            dest_addr: addr,
+            broadcast_radius: 0,
+            options: Some(&options),
+            payload: TRACE_PAYLOAD,
+        })?;
+
+        let mut deadline = Instant::now() + timeout;
+        let mut hops = Vec::new();
+        let mut status_seen = false;
+        while let Some(frame) = self.next_frame(deadline)? {
+            let frame = match frame.downcast::<api::RouteInformation>() {
+                Ok(hop) => {
+                    if hop.dest_addr == addr {
+                        hops.push(*hop);
+                    }
+                    continue;
+                }
+                Err(frame) => frame,
+            };
+            if let Some(status) = frame.downcast_ref::<api::TransmitStatus>() {
+                if status.frame_id() == frame_id {
+                    if !status.delivered() {
+                        return Err(Error::DeliveryError(status.delivery_status()));
+                    }
+                    status_seen = true;
+                    // late hops may still trickle in after the status
+                    deadline = deadline.min(Instant::now() + Duration::from_millis(500));
+                }
+            }
+        }
+
+        if !status_seen {
+            return Err(Error::Timeout);
+        }
+        Ok(hops)
+    }
+
+    pub fn discover_nodes(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.reinitialize_if_reset()?;
+        let frame_id = self.write_frame(&api::AtCommandFrame("ND", None))?;
+        let deadline = Instant::now() + timeout.unwrap_or(Duration::from_secs(15));
+
+        let mut remote_devices: Vec<RemoteDigiMeshDevice> = Vec::new();
+        while let Some(frame) = self.next_frame(deadline)? {
+            if let Some(resp) = frame.downcast_ref::<api::AtCommandResponse>() {
+                if resp.frame_id != frame_id {
+                    continue;
+                }
+                // an empty response marks the end of the discovery
+                match parse_remote_device(resp) {
+                    Some(device) => remote_devices.push(device),
+                    None => break,
+                }
+            }
+        }
+        self.nodes = Some(remote_devices);
 
         Ok(())
     }
 
+    /// Read the neighbor table (FN) of the local module, or of a remote node when
+    /// `addr` is given.
+    pub fn find_neighbors(&mut self, addr: Option<u64>, timeout: Duration) -> Result<Vec<Neighbor>> {
+        self.reinitialize_if_reset()?;
+        let frame_id = match addr {
+            Some(dest_addr) => self.write_frame(&api::RemoteAtCommandFrame {
+                dest_addr,
+                options: &api::RemoteCommandOptions {
+                    apply_changes: false,
+                },
+                atcmd: "FN",
+                cmd_param: None,
+            })?,
+            None => self.write_frame(&api::AtCommandFrame("FN", None))?,
+        };
+        let deadline = Instant::now() + timeout;
+
+        let mut neighbors = Vec::new();
+        while let Some(frame) = self.next_frame(deadline)? {
+            let data = if let Some(resp) = frame.downcast_ref::<api::AtCommandResponse>() {
+                if resp.frame_id != frame_id {
+                    continue;
+                }
+                resp.command_data.as_ref()
+            } else if let Some(resp) = frame.downcast_ref::<api::RemoteAtCommandResponse>() {
+                if resp.frame_id() != frame_id {
+                    continue;
+                }
+                resp.command_data.as_ref()
+            } else {
+                continue;
+            };
+
+            match data.and_then(|buf| parse_neighbor(&buf[..])) {
+                Some(neighbor) => neighbors.push(neighbor),
+                None => break,
+            }
+        }
+
+        Ok(neighbors)
+    }
+
     pub fn scheduled_discover_nodes(&mut self, scan_duration: Duration) -> Result<()> {
         let start_time = Instant::now();
 
@@ -230,25 +959,31 @@ impl DigiMeshDevice {
     
         // Tant que la durée totale du scan n'est pas écoulée...
         while Instant::now().duration_since(start_time) < scan_duration {
+            self.reinitialize_if_reset()?;
             let cycle_start = Instant::now(); // Début du cycle de détection actuel
             // Génère et envoie la commande de découverte.
-            let discover_cmd = api::AtCommandFrame("ND", None).gen()?;
-            self.serial.write(&discover_cmd[..])?;
+            let frame_id = self.write_frame(&api::AtCommandFrame("ND", None))?;
+            let mut detected = Vec::new();
     
             // Définir un timeout court pour chaque cycle de découverte
-            self.serial.set_timeout(Duration::from_secs(5))?;
+            let deadline = cycle_start + Duration::from_secs(5);
     
             // Écoute les réponses pendant le timeout défini
             loop {
-                match api::AtCommandResponse::recieve(self.serial.try_clone()?) {
-                    Ok(resp) => {
-                        if let Some(mut device) = parse_remote_device(&resp) {
+                match self.next_frame(deadline) {
+                    Ok(Some(frame)) => {
+                        let resp = match frame.downcast_ref::<api::AtCommandResponse>() {
+                            Some(resp) if resp.frame_id == frame_id => resp,
+                            _ => continue,
+                        };
+                        if let Some(mut device) = parse_remote_device(resp) {
                             // Trouve l'appareil dans `remote_devices` ou l'ajoute s'il est nouveau
                             let mut found = false;
                             for existing_device in &mut self.nodes.as_mut().unwrap().iter_mut() {
                                 if existing_device.addr_64bit == device.addr_64bit {
                                     // Appareil déjà connu, ajoute la nouvelle période de détection
                                     existing_device.durations.push((cycle_start, Instant::now()));
+                                    detected.push(device.addr_64bit);
                                     found = true;
                                     break;
                                 }
@@ -256,11 +991,14 @@ impl DigiMeshDevice {
                             if !found {
                                 // Nouvel appareil, initialise avec la période de détection actuelle
                                 device.durations.push((cycle_start, Instant::now()));
+                                detected.push(device.addr_64bit);
                                 self.nodes.as_mut().unwrap().push(device);
                             }
+                        } else {
+                            break; // Réponse vide : fin du cycle de découverte
                         }
                     },
-                    Err(api::Error::IOError(ref err)) if err.kind() == std::io::ErrorKind::TimedOut => {
+                    Ok(None) => {
                         break; // Sortie de la boucle si un timeout est atteint
                     },
                     Err(_) => {
@@ -270,6 +1008,25 @@ impl DigiMeshDevice {
                 }
             }
     
+            // Les noeuds qui viennent de répondre sont éveillés : lecture de leur cycle de sommeil,
+            // tentée à nouveau à chaque détection tant qu'elle n'a pas abouti
+            if self.learn_sleep {
+                for addr in detected {
+                    let learned = self.nodes.iter().flatten().any(|n| n.addr_64bit == addr && n.sleep_learned);
+                    if learned {
+                        continue;
+                    }
+                    let cycle = match sleep::learn_sleep_cycle(self, addr) {
+                        Ok(cycle) => cycle,
+                        Err(_) => continue,
+                    };
+                    if let Some(node) = self.nodes.iter_mut().flatten().find(|n| n.addr_64bit == addr) {
+                        node.sleep_cycle = cycle;
+                        node.sleep_learned = true;
+                    }
+                }
+            }
+
             // Petite pause entre les tentatives de découverte pour éviter de surcharger le réseau
             std::thread::sleep(Duration::from_secs(1));
         }
@@ -285,33 +1042,35 @@ impl DigiMeshDevice {
         &mut self,
         frame: T,
     ) -> Result<Box<dyn api::RecieveApiFrame>> {
-        let packet = frame.gen()?; // creats bytes mut
-        self.serial.write(&packet[..])?;
-        let response: Box<dyn api::RecieveApiFrame>;
+        self.reinitialize_if_reset()?;
+        let frame_id = self.write_frame(&frame)?;
 
-        let old_timeout = self.serial.timeout();
-        if frame.id() == api::FrameId::TransmitRequest {
-            response = Box::new(api::TransmitStatus::recieve(self.serial.try_clone()?)?);
-        } else if frame.id() == api::FrameId::AtCommand {
-            self.serial
-                .set_timeout(std::time::Duration::from_millis(100))?;
-            response = Box::new(api::AtCommandResponse::recieve(self.serial.try_clone()?)?);
-        } else if frame.id() == api::FrameId::RemoteAtCommand {
-            self.serial
-                .set_timeout(std::time::Duration::from_millis(3000))?;
-            response = Box::new(api::RemoteAtCommandResponse::recieve(
-                self.serial.try_clone()?,
-            )?);
-        } else {
-            response = Box::new(api::NullRecieve::recieve(self.serial.try_clone()?)?);
-        }
+        let (expected, timeout) = match frame.id() {
+            api::FrameId::TransmitRequest | api::FrameId::ExplicitAddressing => {
+                (api::FrameId::TransmitStatus, TX_STATUS_TIMEOUT)
+            }
+            api::FrameId::AtCommand | api::FrameId::AtCommandQueue => (api::FrameId::AtCommandResponse, Duration::from_millis(1000)),
+            api::FrameId::RemoteAtCommand => {
+                (api::FrameId::RemoteAtCommandResponse, Duration::from_millis(3000))
+            }
+            _ => return Ok(Box::new(api::NullRecieve)),
+        };
 
-        self.serial.set_timeout(old_timeout)?;
-        Ok(response)
+        // only the response to this very frame is returned, anything else
+        // received in between is dispatched
+        let deadline = Instant::now() + timeout;
+        while let Some(response) = self.next_frame(deadline)? {
+            if response.id() == expected && response.request_frame_id() == Some(frame_id) {
+                return Ok(response);
+            }
+        }
+        Err(Error::Timeout)
     }
 
-    /// send an AT command and returns the result
-    pub fn atcmd<'a>(&mut self, atcmd: &'a AtCommand) -> Result<()> {
+    /// Send an AT command in transparent mode and return the lines of the
+    /// response. Multi-line responses are read until an empty line ends them
+    /// or their timeout expires.
+    pub fn atcmd<'a>(&mut self, atcmd: &'a AtCommand) -> Result<Vec<String>> {
         self.tx_buf.clear();
         self.rx_buf.clear();
 
@@ -324,73 +1083,191 @@ impl DigiMeshDevice {
             }
             self.tx_buf.put_u8(0x0d);
         } else {
-            self.tx_buf.put(atcmd.command.as_bytes());
+            self.tx_buf.put(&[self.command_char; 3][..]);
         }
 
-        self.serial.write(&self.tx_buf[..])?;
+        self.serial.write_all(&self.tx_buf[..])?;
+
+        let old_timeout = self.serial.timeout();
+        let deadline = Instant::now() + atcmd.timeout;
+        let mut lines: Vec<String> = Vec::new();
         let mut buf: [u8; 1] = [0; 1];
-        let mut cr_counter = 0;
-        loop {
-            if buf[0] == b'\r' {
-                cr_counter += 1;
-                if cr_counter == atcmd.rcr_len {
-                    break;
-                }
+        let result = loop {
+            let remaining = match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) => remaining,
+                None => break Ok(()),
+            };
+            self.serial.set_timeout(remaining)?;
+            match self.serial.read_exact(&mut buf) {
+                Ok(_) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::TimedOut => break Ok(()),
+                Err(err) => break Err(Error::IOError(err)),
+            }
+            if buf[0] != b'\r' {
+                self.rx_buf.put_u8(buf[0]);
+                continue;
             }
-            self.serial.read_exact(&mut buf)?;
-            self.rx_buf.put_u8(buf[0]);
-        }
 
-        if self.rx_buf.len() < 1 {
-            return Err(Error::IOError(std::io::Error::new(
-                std::io::ErrorKind::UnexpectedEof,
-                "RX buf empty",
-            )));
+            let line = String::from_utf8_lossy(&self.rx_buf[..]).to_string();
+            self.rx_buf.clear();
+            // ND ends with an empty line after the one closing the last record
+            let end = !atcmd.multi_line
+                || line == "ERROR"
+                || (line.is_empty() && lines.last().map_or(true, |l| l.is_empty()));
+            lines.push(line);
+            if end {
+                break Ok(());
+            }
+        };
+        self.serial.set_timeout(old_timeout)?;
+        result?;
+
+        if lines.is_empty() && !atcmd.multi_line {
+            return Err(Error::Timeout);
         }
-        Ok(())
+        Ok(lines)
     }
 
+    /// Enter command mode with the command sequence surrounded by the guard
+    /// times, and read GT and CC for the next time, or leave it with CN.
     pub fn command_mode(&mut self, mode: bool) -> Result<()> {
         match mode {
             true => {
-                thread::sleep(Duration::from_millis(1000));
-                self.atcmd(&AtCommands::CmdMode(true).create())?;
-                thread::sleep(Duration::from_millis(1000));
+                thread::sleep(self.guard_time);
+                let mut cmd = AtCommands::CmdMode(true).create();
+                // the module answers once the second guard time is over
+                cmd.timeout = self.guard_time + Duration::from_secs(2);
+                match self.atcmd(&cmd) {
+                    Ok(ref resp) if resp.first().map(|l| l.as_str()) == Some("OK") => {}
+                    Ok(_) | Err(Error::Timeout) => return Err(Error::NoCommandMode),
+                    Err(err) => return Err(err),
+                }
+
+                let gt = self.atcmd(&AtCommands::AtCmd(("GT", None)).create())?;
+                if let Some(Ok(gt)) = gt.first().map(|l| u64::from_str_radix(l, 16)) {
+                    self.guard_time = Duration::from_millis(gt);
+                }
+                let cc = self.atcmd(&AtCommands::AtCmd(("CC", None)).create())?;
+                if let Some(Ok(cc)) = cc.first().map(|l| u8::from_str_radix(l, 16)) {
+                    self.command_char = cc;
+                }
             }
             false => {
-                self.atcmd(&AtCommands::CmdMode(false).create())?;
+                let resp = self.atcmd(&AtCommands::CmdMode(false).create())?;
+                if resp.first().map(|l| l.as_str()) != Some("OK") {
+                    return Err(Error::InvalidMode(
+                        "Module did not leave command mode".to_string(),
+                    ));
+                }
             }
         }
         Ok(())
     }
 }
 
-fn parse_remote_device(rd: &api::AtCommandResponse) -> Option<RemoteDigiMeshDevice> {
-    if let Some(buf) = &rd.command_data {
-        if buf.len() < 10 {
-            return None;
-        }
+/// Split a node record as returned by ND and FN into its 64-bit address, its
+/// node identifier and whatever follows the NI terminator.
+fn parse_node_record(buf: &[u8]) -> Option<(u64, String, &[u8])> {
+    if buf.len() < 10 {
+        return None;
+    }
 
-        let addr = u64::from_be_bytes(buf.get(2..10)?.try_into().ok()?);
+    let addr = u64::from_be_bytes(buf.get(2..10)?.try_into().ok()?);
 
-        let mut end_idx = 10;
-        for i in 10..buf.len() - 1 {
-            if buf[i] == 0 {
-                break;
-            }
-            end_idx += 1;
+    let mut end_idx = 10;
+    for i in 10..buf.len() - 1 {
+        if buf[i] == 0 {
+            break;
         }
+        end_idx += 1;
+    }
 
-        let node_id = String::from_utf8(buf.get(10..end_idx)?.to_vec()).ok()?;
+    let node_id = String::from_utf8(buf.get(10..end_idx)?.to_vec()).ok()?;
+    let tail = buf.get(end_idx + 1..).unwrap_or(&[]);
 
-        Some(RemoteDigiMeshDevice {
-            addr_64bit: addr,
-            node_id,
-            firmware_version: None,
-            hardware_version: None,
-            durations: Vec::new(),
-        })
-    } else {
-        None
+    Some((addr, node_id, tail))
+}
+
+fn parse_remote_device(rd: &api::AtCommandResponse) -> Option<RemoteDigiMeshDevice> {
+    let buf = rd.command_data.as_ref()?;
+    let (addr, node_id, tail) = parse_node_record(&buf[..])?;
+
+    Some(RemoteDigiMeshDevice {
+        addr_64bit: addr,
+        node_id,
+        role: parse_role(tail),
+        vendor: None,
+        firmware_version: None,
+        hardware_version: None,
+        inventory: None,
+        durations: Vec::new(),
+        sleep_cycle: None,
+        sleep_learned: false,
+    })
+}
+
+fn parse_neighbor(buf: &[u8]) -> Option<Neighbor> {
+    let (addr, node_id, tail) = parse_node_record(buf)?;
+
+    // parent(2) device type(1) status(1) profile(2) manufacturer(2), then DD(4)
+    // when NO has bit 0 set and the RSSI of the link(1) when NO has bit 2 set
+    let rssi = match tail.len() {
+        9 | 13 => tail.last().copied(),
+        _ => None,
+    };
+
+    Some(Neighbor {
+        addr_64bit: addr,
+        node_id,
+        role: parse_role(tail),
+        rssi,
+    })
+}
+
+fn parse_role(tail: &[u8]) -> DeviceRole {
+    match tail.get(2) {
+        Some(device_type) => DeviceRole::from_device_type(*device_type),
+        None => DeviceRole::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn neighbor_record(tail: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0xff, 0xfe];
+        buf.extend_from_slice(&0x0013a20040a1b2c3u64.to_be_bytes());
+        buf.extend_from_slice(b"ROUTER-1\0");
+        buf.extend_from_slice(&[0xff, 0xfe, 0x01, 0x00, 0xc1, 0x05, 0x10, 0x1e]);
+        buf.extend_from_slice(tail);
+        buf
+    }
+
+    #[test]
+    fn parse_neighbor_without_options() {
+        let neighbor = parse_neighbor(&neighbor_record(&[])).unwrap();
+        assert_eq!(neighbor.addr_64bit, 0x0013a20040a1b2c3);
+        assert_eq!(neighbor.node_id, "ROUTER-1");
+        assert_eq!(neighbor.role, DeviceRole::Router);
+        assert_eq!(neighbor.rssi, None);
+    }
+
+    #[test]
+    fn parse_neighbor_with_rssi() {
+        let neighbor = parse_neighbor(&neighbor_record(&[0x28])).unwrap();
+        assert_eq!(neighbor.rssi, Some(0x28));
+    }
+
+    #[test]
+    fn parse_neighbor_with_dd_only() {
+        let neighbor = parse_neighbor(&neighbor_record(&[0x00, 0x0c, 0x00, 0x00])).unwrap();
+        assert_eq!(neighbor.rssi, None);
+    }
+
+    #[test]
+    fn parse_neighbor_with_dd_and_rssi() {
+        let neighbor = parse_neighbor(&neighbor_record(&[0x00, 0x0c, 0x00, 0x00, 0x28])).unwrap();
+        assert_eq!(neighbor.rssi, Some(0x28));
     }
 }
\ No newline at end of file
@@ -0,0 +1,220 @@
+//!
+//! Graphviz DOT and GraphML rendering of the discovered network
+//!
+//!
+
+use crate::discover::{DeviceRole, RemoteDigiMeshDevice};
+use crate::topology::{Link, Topology};
+use std::collections::BTreeMap;
+
+struct GraphNode {
+    node_id: String,
+    role: DeviceRole,
+    local: bool,
+    hops: Option<usize>,
+}
+
+/// Snapshot of the nodes and links to render
+pub struct Graph {
+    nodes: BTreeMap<u64, GraphNode>,
+    links: Vec<Link>,
+}
+
+impl Graph {
+    /// Merge the local module, the discovered nodes and the topology (if any)
+    /// into one graph. Hop counts are measured from the local module.
+    pub fn new(
+        local: (u64, &str, DeviceRole),
+        nodes: &[RemoteDigiMeshDevice],
+        topology: Option<&Topology>,
+    ) -> Self {
+        let mut graph_nodes = BTreeMap::new();
+        graph_nodes.insert(
+            local.0,
+            GraphNode {
+                node_id: local.1.to_string(),
+                role: local.2,
+                local: true,
+                hops: Some(0),
+            },
+        );
+
+        for node in nodes {
+            graph_nodes.entry(node.addr_64bit).or_insert(GraphNode {
+                node_id: node.node_id.clone(),
+                role: node.role,
+                local: false,
+                hops: None,
+            });
+        }
+
+        let mut links = Vec::new();
+        if let Some(topology) = topology {
+            for (addr, node_id) in topology.nodes() {
+                let entry = graph_nodes.entry(addr).or_insert(GraphNode {
+                    node_id: node_id.to_string(),
+                    role: DeviceRole::Unknown,
+                    local: false,
+                    hops: None,
+                });
+                if entry.role == DeviceRole::Unknown {
+                    entry.role = topology.role(addr);
+                }
+                entry.hops = topology.hop_count(local.0, addr);
+            }
+            links = topology.links();
+        }
+
+        Self {
+            nodes: graph_nodes,
+            links,
+        }
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("graph mesh {\n");
+        out.push_str("    node [style=filled];\n");
+
+        for (addr, node) in &self.nodes {
+            let mut label = format!("{}\\n{:016x}", escape_dot(&node.node_id), addr);
+            if let Some(hops) = node.hops {
+                label.push_str(&format!("\\n{} hop(s)", hops));
+            }
+            let shape = if node.local { "doublecircle" } else { "ellipse" };
+            out.push_str(&format!(
+                "    \"{:016x}\" [label=\"{}\", shape={}, fillcolor=\"{}\"];\n",
+                addr,
+                label,
+                shape,
+                role_color(node.role)
+            ));
+        }
+
+        for link in &self.links {
+            let label = match link.rssi {
+                Some(rssi) => format!(" [label=\"-{} dBm\"]", rssi),
+                None => String::new(),
+            };
+            out.push_str(&format!(
+                "    \"{:016x}\" -- \"{:016x}\"{};\n",
+                link.a, link.b, label
+            ));
+        }
+
+        out.push_str("}\n");
+        out
+    }
+
+    pub fn to_graphml(&self) -> String {
+        let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        out.push_str("<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n");
+        out.push_str("  <key id=\"ni\" for=\"node\" attr.name=\"node_id\" attr.type=\"string\"/>\n");
+        out.push_str("  <key id=\"addr\" for=\"node\" attr.name=\"address\" attr.type=\"string\"/>\n");
+        out.push_str("  <key id=\"role\" for=\"node\" attr.name=\"role\" attr.type=\"string\"/>\n");
+        out.push_str("  <key id=\"color\" for=\"node\" attr.name=\"color\" attr.type=\"string\"/>\n");
+        out.push_str("  <key id=\"hops\" for=\"node\" attr.name=\"hops\" attr.type=\"int\"/>\n");
+        out.push_str("  <key id=\"rssi\" for=\"edge\" attr.name=\"rssi\" attr.type=\"int\"/>\n");
+        out.push_str("  <graph id=\"mesh\" edgedefault=\"undirected\">\n");
+
+        for (addr, node) in &self.nodes {
+            out.push_str(&format!("    <node id=\"n{:016x}\">\n", addr));
+            out.push_str(&format!(
+                "      <data key=\"ni\">{}</data>\n",
+                escape_xml(&node.node_id)
+            ));
+            out.push_str(&format!("      <data key=\"addr\">{:016x}</data>\n", addr));
+            let role = if node.local { "local" } else { node.role.name() };
+            out.push_str(&format!("      <data key=\"role\">{}</data>\n", role));
+            out.push_str(&format!(
+                "      <data key=\"color\">{}</data>\n",
+                role_color(node.role)
+            ));
+            if let Some(hops) = node.hops {
+                out.push_str(&format!("      <data key=\"hops\">{}</data>\n", hops));
+            }
+            out.push_str("    </node>\n");
+        }
+
+        for link in &self.links {
+            out.push_str(&format!(
+                "    <edge source=\"n{:016x}\" target=\"n{:016x}\">\n",
+                link.a, link.b
+            ));
+            if let Some(rssi) = link.rssi {
+                out.push_str(&format!("      <data key=\"rssi\">-{}</data>\n", rssi));
+            }
+            out.push_str("    </edge>\n");
+        }
+
+        out.push_str("  </graph>\n");
+        out.push_str("</graphml>\n");
+        out
+    }
+}
+
+fn role_color(role: DeviceRole) -> &'static str {
+    match role {
+        DeviceRole::Coordinator => "gold",
+        DeviceRole::Router => "lightblue",
+        DeviceRole::EndDevice => "palegreen",
+        DeviceRole::Unknown => "lightgrey",
+    }
+}
+
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::topology::Neighbor;
+
+    fn graph() -> Graph {
+        let mut topology = Topology::new();
+        topology.add_neighbors(
+            1,
+            &[Neighbor {
+                addr_64bit: 2,
+                node_id: "say \"hi\" <&>".to_string(),
+                role: DeviceRole::Router,
+                rssi: Some(62),
+            }],
+        );
+        Graph::new((1, "GW\\1", DeviceRole::Coordinator), &[], Some(&topology))
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(escape_dot("a\\b\"c"), "a\\\\b\\\"c");
+        assert_eq!(escape_xml("<a & \"b\">"), "&lt;a &amp; &quot;b&quot;&gt;");
+    }
+
+    #[test]
+    fn dot_output() {
+        let dot = graph().to_dot();
+        assert!(dot.contains(
+            "\"0000000000000001\" [label=\"GW\\\\1\\n0000000000000001\\n0 hop(s)\", shape=doublecircle, fillcolor=\"gold\"];"
+        ));
+        assert!(dot.contains("label=\"say \\\"hi\\\" <&>\\n0000000000000002\\n1 hop(s)\""));
+        assert!(dot.contains("\"0000000000000001\" -- \"0000000000000002\" [label=\"-62 dBm\"];"));
+    }
+
+    #[test]
+    fn graphml_output() {
+        let graphml = graph().to_graphml();
+        assert!(graphml.contains("<data key=\"ni\">say &quot;hi&quot; &lt;&amp;&gt;</data>"));
+        assert!(graphml.contains("<data key=\"role\">local</data>"));
+        assert!(graphml.contains("<data key=\"hops\">1</data>"));
+        assert!(graphml.contains(
+            "<edge source=\"n0000000000000001\" target=\"n0000000000000002\">\n      <data key=\"rssi\">-62</data>"
+        ));
+    }
+}
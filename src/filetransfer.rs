@@ -0,0 +1,446 @@
+//!
+//! Reliable file transfer between mesh nodes
+//!
+//! Files are cut in chunks that fit in a single RF packet and sent with a
+//! go-back-N sliding window acknowledged by the receiver. An interrupted
+//! transfer resumes from the partial file left on the receiving side, and the
+//! result is verified against the SHA-256 announced in the offer.
+//!
+//! Every packet starts with the magic byte, the packet type and the transfer id:
+//!
+//! OFFER:  size (8), chunk size (2), sha256 (32), file name
+//! ACCEPT: first chunk to send (4)
+//! DATA:   chunk index (4), chunk data
+//! ACK:    next chunk expected (4)
+//! DONE:   verified (1)
+//!
+
+use crate::api::DeliveryStatus;
+use crate::discover::{self, DigiMeshDevice};
+use bytes::{BufMut, BytesMut};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+static MAGIC: u8 = 0xf8;
+static HEADER_LEN: usize = 6;
+static OFFER_FIELDS_LEN: usize = 42; // size, chunk size and sha256
+static OFFER: u8 = 0x01;
+static ACCEPT: u8 = 0x02;
+static DATA: u8 = 0x03;
+static ACK: u8 = 0x04;
+static DONE: u8 = 0x05;
+
+#[derive(Debug)]
+pub enum Error {
+    DeviceError(discover::Error),
+    IOError(std::io::Error),
+    ProtocolError(String),
+    HashMismatch,
+    Timeout,
+}
+
+impl From<discover::Error> for Error {
+    fn from(err: discover::Error) -> Self {
+        Error::DeviceError(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::IOError(err)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Error::DeviceError(ref err) => write!(f, "{}", err),
+            Error::IOError(ref err) => write!(f, "{}", err),
+            Error::ProtocolError(ref err) => write!(f, "{}", err),
+            Error::HashMismatch => write!(f, "SHA-256 of the received file does not match"),
+            Error::Timeout => write!(f, "Peer stopped responding"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub struct TransferOptions {
+    pub window: u32,
+    pub ack_timeout: Duration,
+    pub max_retries: u32,
+}
+
+impl Default for TransferOptions {
+    fn default() -> Self {
+        Self {
+            window: 8,
+            ack_timeout: Duration::from_secs(5),
+            max_retries: 10,
+        }
+    }
+}
+
+struct Packet {
+    kind: u8,
+    transfer_id: u32,
+    body: BytesMut,
+}
+
+impl Packet {
+    fn new(kind: u8, transfer_id: u32) -> Self {
+        Self {
+            kind,
+            transfer_id,
+            body: BytesMut::new(),
+        }
+    }
+
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < HEADER_LEN || data[0] != MAGIC {
+            return None;
+        }
+        Some(Self {
+            kind: data[1],
+            transfer_id: u32::from_be_bytes(<[u8; 4]>::try_from(&data[2..6]).unwrap()),
+            body: BytesMut::from(&data[HEADER_LEN..]),
+        })
+    }
+
+    fn u32_at(&self, idx: usize) -> Option<u32> {
+        let bytes = self.body.get(idx..idx + 4)?;
+        Some(u32::from_be_bytes(<[u8; 4]>::try_from(bytes).unwrap()))
+    }
+
+    fn encode(&self) -> BytesMut {
+        let mut packet = BytesMut::with_capacity(HEADER_LEN + self.body.len());
+        packet.put_u8(MAGIC);
+        packet.put_u8(self.kind);
+        packet.put_u32(self.transfer_id);
+        packet.put(&self.body[..]);
+        packet
+    }
+
+    /// Packets that fail to be delivered are recovered by the retransmissions of
+    /// the protocol, so only local failures, such as a packet larger than NP,
+    /// are reported.
+    fn send(&self, device: &mut DigiMeshDevice, addr: u64) -> Result<()> {
+        match device.send_data(addr, &self.encode()[..], None) {
+            Ok(_) | Err(discover::Error::Timeout) => Ok(()),
+            Err(discover::Error::DeliveryError(status)) => match status {
+                DeliveryStatus::MacAckFailure
+                | DeliveryStatus::CcaFailure
+                | DeliveryStatus::NetworkAckFailure
+                | DeliveryStatus::AddressNotFound
+                | DeliveryStatus::RouteNotFound
+                | DeliveryStatus::ResourceError => Ok(()),
+                status => Err(Error::DeviceError(discover::Error::DeliveryError(status))),
+            },
+            Err(err) => Err(Error::DeviceError(err)),
+        }
+    }
+}
+
+/// Wait for the next packet of `transfer_id` coming from `addr`
+fn recv_packet(
+    device: &mut DigiMeshDevice,
+    addr: u64,
+    transfer_id: u32,
+    timeout: Duration,
+) -> Result<Option<Packet>> {
+    let deadline = Instant::now() + timeout;
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        let received = match device.recv_data(remaining)? {
+            Some(received) => received,
+            None => break,
+        };
+        if received.source_addr != addr {
+            continue;
+        }
+        match Packet::parse(&received.data[..]) {
+            Some(packet) if packet.transfer_id == transfer_id => return Ok(Some(packet)),
+            _ => continue,
+        }
+    }
+    Ok(None)
+}
+
+fn sha256_file(path: &Path) -> Result<[u8; 32]> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    let mut digest = [0u8; 32];
+    digest.copy_from_slice(&hasher.finalize()[..]);
+    Ok(digest)
+}
+
+/// Send the file at `path` to the node `addr`, which must be running `receive_file`
+pub fn send_file(
+    device: &mut DigiMeshDevice,
+    addr: u64,
+    path: &Path,
+    opts: &TransferOptions,
+) -> Result<()> {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| Error::ProtocolError("Invalid file name".to_string()))?;
+    let size = fs::metadata(path)?.len();
+    let digest = sha256_file(path)?;
+    let max_payload = device.get_max_payload()?;
+    let chunk_size = max_payload.saturating_sub(HEADER_LEN + 4);
+    if chunk_size == 0 {
+        return Err(Error::ProtocolError("Maximum payload too small".to_string()));
+    }
+    // the offer carries the name and must fit in a single packet as well
+    let max_name_len = max_payload.saturating_sub(HEADER_LEN + OFFER_FIELDS_LEN);
+    if name.len() > max_name_len {
+        return Err(Error::ProtocolError(format!(
+            "File name too long, {} bytes at most",
+            max_name_len
+        )));
+    }
+    let chunks = size.div_ceil(chunk_size as u64) as u32;
+    let mut rng = rand::thread_rng();
+    let transfer_id: u32 = rng.gen();
+
+    let mut offer = Packet::new(OFFER, transfer_id);
+    offer.body.put_u64(size);
+    offer.body.put_u16(chunk_size as u16);
+    offer.body.put(&digest[..]);
+    offer.body.put(name.as_bytes());
+
+    // offer until the receiver tells where to start from
+    let mut base = None;
+    for _ in 0..opts.max_retries {
+        offer.send(device, addr)?;
+        if let Some(packet) = recv_packet(device, addr, transfer_id, opts.ack_timeout)? {
+            if packet.kind == ACCEPT {
+                base = packet.u32_at(0);
+                break;
+            }
+        }
+    }
+    let mut base = base.ok_or(Error::Timeout)?.min(chunks);
+
+    let mut file = File::open(path)?;
+    let mut buf = vec![0u8; chunk_size];
+    let mut next = base;
+    let mut retries = 0;
+
+    loop {
+        while next < chunks && next < base + opts.window {
+            file.seek(SeekFrom::Start(next as u64 * chunk_size as u64))?;
+            let len = ((size - next as u64 * chunk_size as u64) as usize).min(chunk_size);
+            file.read_exact(&mut buf[..len])?;
+
+            let mut data = Packet::new(DATA, transfer_id);
+            data.body.put_u32(next);
+            data.body.put(&buf[..len]);
+            data.send(device, addr)?;
+            next += 1;
+        }
+
+        match recv_packet(device, addr, transfer_id, opts.ack_timeout)? {
+            Some(packet) if packet.kind == ACK => {
+                if let Some(acked) = packet.u32_at(0) {
+                    if acked > base {
+                        base = acked.min(chunks);
+                        retries = 0;
+                    }
+                }
+            }
+            Some(packet) if packet.kind == DONE => {
+                return match packet.body.first() {
+                    Some(1) => Ok(()),
+                    _ => Err(Error::HashMismatch),
+                };
+            }
+            Some(_) => {}
+            None => {
+                // go back to the first unacknowledged chunk
+                retries += 1;
+                if retries > opts.max_retries {
+                    return Err(Error::Timeout);
+                }
+                next = base;
+                if base == chunks && chunks > 0 {
+                    // everything was acknowledged but DONE was lost, poke the receiver
+                    next = chunks - 1;
+                }
+            }
+        }
+    }
+}
+
+/// Wait up to `timeout` for a file offer and store the file in `dir`. Returns
+/// the path of the verified file.
+pub fn receive_file(
+    device: &mut DigiMeshDevice,
+    dir: &Path,
+    timeout: Duration,
+    opts: &TransferOptions,
+) -> Result<PathBuf> {
+    // wait for an offer from any node
+    let deadline = Instant::now() + timeout;
+    let (addr, offer) = loop {
+        let remaining = deadline
+            .checked_duration_since(Instant::now())
+            .ok_or(Error::Timeout)?;
+        let received = device.recv_data(remaining)?.ok_or(Error::Timeout)?;
+        match Packet::parse(&received.data[..]) {
+            Some(packet) if packet.kind == OFFER && packet.body.len() > OFFER_FIELDS_LEN => {
+                break (received.source_addr, packet)
+            }
+            _ => continue,
+        }
+    };
+
+    let transfer_id = offer.transfer_id;
+    let size = u64::from_be_bytes(<[u8; 8]>::try_from(&offer.body[0..8]).unwrap());
+    let chunk_size = u16::from_be_bytes([offer.body[8], offer.body[9]]) as u64;
+    let digest = &offer.body[10..OFFER_FIELDS_LEN];
+    let name = std::str::from_utf8(&offer.body[OFFER_FIELDS_LEN..])
+        .map_err(|_| Error::ProtocolError("Invalid file name".to_string()))?;
+    // never let the sender pick a path outside of `dir`
+    let name = Path::new(name)
+        .file_name()
+        .ok_or_else(|| Error::ProtocolError("Invalid file name".to_string()))?;
+    if chunk_size == 0 {
+        return Err(Error::ProtocolError("Invalid chunk size".to_string()));
+    }
+    let chunks = size.div_ceil(chunk_size) as u32;
+
+    // the partial file is tied to the content, so a resumed transfer cannot
+    // mix two versions of a file
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    let part_path = dir.join(format!(".{}.{}.part", name.to_string_lossy(), hex));
+    let final_path = dir.join(name);
+
+    // the partial file is kept to resume the transfer
+    let mut part = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(&part_path)?;
+    let mut expected = (part.metadata()?.len() / chunk_size).min(chunks as u64) as u32;
+    part.set_len(expected as u64 * chunk_size)?;
+    part.seek(SeekFrom::End(0))?;
+
+    let mut accept = Packet::new(ACCEPT, transfer_id);
+    accept.body.put_u32(expected);
+    accept.send(device, addr)?;
+
+    let mut retries = 0;
+    while expected < chunks {
+        let packet = match recv_packet(device, addr, transfer_id, opts.ack_timeout)? {
+            Some(packet) => packet,
+            None => {
+                retries += 1;
+                if retries > opts.max_retries {
+                    return Err(Error::Timeout);
+                }
+                continue;
+            }
+        };
+        retries = 0;
+
+        if packet.kind == OFFER {
+            // our ACCEPT was lost
+            accept.send(device, addr)?;
+            continue;
+        }
+        if packet.kind != DATA {
+            continue;
+        }
+        if packet.u32_at(0) == Some(expected) {
+            part.write_all(&packet.body[4..])?;
+            expected += 1;
+        }
+
+        // cumulative ack, also sent for out of order chunks to speed up the retransmission
+        let mut ack = Packet::new(ACK, transfer_id);
+        ack.body.put_u32(expected);
+        ack.send(device, addr)?;
+    }
+    part.flush()?;
+    drop(part);
+
+    let verified = sha256_file(&part_path)?[..] == digest[..];
+    if verified {
+        fs::rename(&part_path, &final_path)?;
+    } else {
+        fs::remove_file(&part_path)?;
+    }
+
+    let mut done = Packet::new(DONE, transfer_id);
+    done.body.put_u8(verified as u8);
+    done.send(device, addr)?;
+
+    // stay around in case DONE was lost and the sender retransmits
+    while let Some(packet) = recv_packet(device, addr, transfer_id, opts.ack_timeout * 2)? {
+        if packet.kind == DATA {
+            done.send(device, addr)?;
+        }
+    }
+
+    if verified {
+        Ok(final_path)
+    } else {
+        Err(Error::HashMismatch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packet_round_trip() {
+        let mut packet = Packet::new(DATA, 0xdeadbeef);
+        packet.body.put_u32(7);
+        packet.body.put(&b"chunk"[..]);
+        let encoded = packet.encode();
+        assert_eq!(&encoded[..6], &[MAGIC, DATA, 0xde, 0xad, 0xbe, 0xef]);
+
+        let decoded = Packet::parse(&encoded[..]).unwrap();
+        assert_eq!(decoded.kind, DATA);
+        assert_eq!(decoded.transfer_id, 0xdeadbeef);
+        assert_eq!(decoded.u32_at(0), Some(7));
+        assert_eq!(&decoded.body[4..], b"chunk");
+        assert_eq!(decoded.u32_at(6), None);
+    }
+
+    #[test]
+    fn offer_fields_fit_the_announced_length() {
+        let mut offer = Packet::new(OFFER, 1);
+        offer.body.put_u64(1000);
+        offer.body.put_u16(64);
+        offer.body.put(&[0u8; 32][..]);
+        assert_eq!(offer.body.len(), OFFER_FIELDS_LEN);
+        assert_eq!(offer.encode().len(), HEADER_LEN + OFFER_FIELDS_LEN);
+    }
+
+    #[test]
+    fn parse_rejects_foreign_data() {
+        assert!(Packet::parse(&[MAGIC, ACK, 0, 0, 0]).is_none());
+        assert!(Packet::parse(&[0x00, ACK, 0, 0, 0, 1]).is_none());
+        let empty = Packet::parse(&[MAGIC, DONE, 0, 0, 0, 1]).unwrap();
+        assert!(empty.body.is_empty());
+    }
+}
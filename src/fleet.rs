@@ -0,0 +1,456 @@
+#![allow(dead_code)]
+//!
+//! Fleet configuration: push a desired set of AT parameters to every node
+//!
+//! The desired configuration is a YAML document:
+//!
+//! ```yaml
+//! defaults:
+//!   CH: 0x0C
+//!   ID: 0x7FFF
+//! nodes:
+//!   - match: "SENSOR-*"
+//!     params:
+//!       SM: 8
+//!       SP: 0x1F4
+//!   - match: "GATEWAY"
+//!     params:
+//!       NI: "GATEWAY"
+//! ```
+//!
+//! Numbers are sent as big endian integers, strings starting with `0x` as raw
+//! bytes and other strings as text. Rules are matched against the node
+//! identifier and applied in order, so a later rule overrides an earlier one.
+//! Write-only parameters (KY) cannot be compared with the node and are left
+//! out; the network key is managed with the `security` command.
+//!
+
+use crate::discover::{self, DeviceRole, DigiMeshDevice, NodeTarget};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+
+/// Parameters that move a node to another network, applied once every other
+/// change went through
+pub(crate) static NETWORK_PARAMS: [&str; 6] = ["ID", "CH", "HP", "CM", "EE", "KY"];
+
+/// Parameters that cannot be read back, left out of the plans
+static WRITE_ONLY_PARAMS: [&str; 1] = ["KY"];
+
+#[derive(Debug)]
+pub enum Error {
+    DeviceError(discover::Error),
+    IOError(std::io::Error),
+    ParseError(serde_yaml::Error),
+    InvalidValue(String),
+}
+
+impl From<discover::Error> for Error {
+    fn from(err: discover::Error) -> Self {
+        Error::DeviceError(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::IOError(err)
+    }
+}
+
+impl From<serde_yaml::Error> for Error {
+    fn from(err: serde_yaml::Error) -> Self {
+        Error::ParseError(err)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Error::DeviceError(ref err) => write!(f, "{}", err),
+            Error::IOError(ref err) => write!(f, "{}", err),
+            Error::ParseError(ref err) => write!(f, "{}", err),
+            Error::InvalidValue(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum ParamValue {
+    Number(u64),
+    Text(String),
+}
+
+impl ParamValue {
+    /// parameter bytes of the AT command
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        match self {
+            ParamValue::Number(val) => {
+                let bytes = val.to_be_bytes();
+                let start = bytes.iter().position(|b| *b != 0).unwrap_or(7);
+                Ok(bytes[start..].to_vec())
+            }
+            ParamValue::Text(text) => match text.strip_prefix("0x") {
+                Some(hex) => {
+                    let hex = if hex.len() % 2 == 1 {
+                        format!("0{}", hex)
+                    } else {
+                        hex.to_string()
+                    };
+                    (0..hex.len())
+                        .step_by(2)
+                        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                        .collect::<std::result::Result<Vec<u8>, _>>()
+                        .map_err(|_| Error::InvalidValue(format!("Invalid hex value {}", text)))
+                }
+                None => Ok(text.as_bytes().to_vec()),
+            },
+        }
+    }
+
+    /// Whether the value read from a node is this one
+    fn matches(&self, current: &[u8]) -> bool {
+        match self {
+            ParamValue::Text(text) if !text.starts_with("0x") => current == text.as_bytes(),
+            _ => match self.encode() {
+                // numbers are compared without their leading zeros
+                Ok(wanted) => strip_zeros(&wanted[..]) == strip_zeros(current),
+                Err(_) => false,
+            },
+        }
+    }
+
+    fn display(&self) -> String {
+        match self {
+            ParamValue::Number(val) => format!("{:x}", val),
+            ParamValue::Text(text) => text.clone(),
+        }
+    }
+}
+
+fn strip_zeros(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+fn display_bytes(param: &str, bytes: &[u8]) -> String {
+    if param == "NI" {
+        String::from_utf8_lossy(bytes).to_string()
+    } else {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NodeRule {
+    /// glob on the node identifier, `*` and `?` are supported
+    #[serde(rename = "match")]
+    pub pattern: String,
+    pub params: BTreeMap<String, ParamValue>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FleetConfig {
+    #[serde(default)]
+    pub defaults: BTreeMap<String, ParamValue>,
+    #[serde(default)]
+    pub nodes: Vec<NodeRule>,
+}
+
+/// Match `text` against a glob made of literals, `*` and `?`
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // let the last star swallow one more character
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+impl FleetConfig {
+    pub fn load(path: &str) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        Self::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut config: FleetConfig = serde_yaml::from_str(contents)?;
+        config.defaults = uppercase(config.defaults);
+        for rule in config.nodes.iter_mut() {
+            rule.params = uppercase(std::mem::take(&mut rule.params));
+        }
+        Ok(config)
+    }
+
+    /// Parameters wanted on the node named `node_id`
+    pub fn desired(&self, node_id: &str) -> BTreeMap<String, ParamValue> {
+        let mut params = self.defaults.clone();
+        for rule in &self.nodes {
+            if glob_match(&rule.pattern, node_id) {
+                params.extend(rule.params.clone());
+            }
+        }
+        params
+    }
+}
+
+fn uppercase(params: BTreeMap<String, ParamValue>) -> BTreeMap<String, ParamValue> {
+    params
+        .into_iter()
+        .map(|(param, value)| (param.to_uppercase(), value))
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub param: String,
+    pub old: String,
+    pub new: ParamValue,
+}
+
+impl Change {
+    pub fn new_display(&self) -> String {
+        self.new.display()
+    }
+}
+
+/// Changes needed on one node
+#[derive(Debug, Clone)]
+pub struct NodePlan {
+    pub target: NodeTarget,
+    pub node_id: String,
+    pub role: DeviceRole,
+    pub changes: Vec<Change>,
+    pub failed: Vec<(String, String)>, // parameters that could not be read
+}
+
+impl NodePlan {
+    fn is_network(change: &Change) -> bool {
+        NETWORK_PARAMS.contains(&change.param.as_str())
+    }
+}
+
+/// Outcome of the configuration of one node
+#[derive(Debug, Clone)]
+pub struct NodeReport {
+    pub target: NodeTarget,
+    pub node_id: String,
+    pub changed: Vec<Change>,
+    pub failed: Vec<(String, String)>,
+    pub written: bool,
+}
+
+/// Compare the desired configuration with the one of the local module and of
+/// every discovered node. Remote nodes are ordered with the coordinators last,
+/// followed by the local module.
+pub fn plan(device: &mut DigiMeshDevice, config: &FleetConfig) -> Result<Vec<NodePlan>> {
+    let mut targets: Vec<(NodeTarget, String, DeviceRole)> = device
+        .nodes
+        .iter()
+        .flatten()
+        .map(|n| (NodeTarget::Remote(n.addr_64bit), n.node_id.clone(), n.role))
+        .collect();
+    targets.sort_by_key(|(_, _, role)| *role == DeviceRole::Coordinator);
+    let local_id = device.get_node_id()?;
+    targets.push((NodeTarget::Local, local_id, DeviceRole::Unknown));
+
+    let mut plans = Vec::new();
+    for (target, node_id, role) in targets {
+        let mut plan = NodePlan {
+            target,
+            node_id: node_id.clone(),
+            role,
+            changes: Vec::new(),
+            failed: Vec::new(),
+        };
+        for (param, value) in config.desired(&node_id) {
+            if WRITE_ONLY_PARAMS.contains(&param.as_str()) {
+                continue;
+            }
+            match device.get_param(target, &param) {
+                Ok(current) if value.matches(&current[..]) => {}
+                Ok(current) => plan.changes.push(Change {
+                    old: display_bytes(&param, &current[..]),
+                    param,
+                    new: value,
+                }),
+                Err(err) => plan.failed.push((param, err.to_string())),
+            }
+        }
+        plans.push(plan);
+    }
+    Ok(plans)
+}
+
+/// Queue `changes` on the node, save them with WR then apply them with AC.
+/// The local module applies its queued values with the WR frame, all at once.
+fn push_changes(
+    device: &mut DigiMeshDevice,
+    target: NodeTarget,
+    changes: &[Change],
+    report: &mut NodeReport,
+) -> bool {
+    let mut queued = false;
+    for change in changes {
+        let set = change
+            .new
+            .encode()
+            .and_then(|value| Ok(device.queue_param(target, &change.param, &value[..])?));
+        match set {
+            Ok(_) => {
+                report.changed.push(change.clone());
+                queued = true;
+            }
+            Err(err) => report.failed.push((change.param.clone(), err.to_string())),
+        }
+    }
+    if !queued {
+        return false;
+    }
+
+    let written = match target {
+        NodeTarget::Local => device.execute(target, "WR", None).map(|_| ()),
+        NodeTarget::Remote(addr) => device.remote_write(addr),
+    };
+    if let Err(err) = written {
+        report.failed.push(("WR".to_string(), err.to_string()));
+        return false;
+    }
+    let applied = match target {
+        NodeTarget::Local => device.execute(target, "AC", None).map(|_| ()),
+        NodeTarget::Remote(addr) => device.remote_apply(addr),
+    };
+    match applied {
+        // a node moved to the new network cannot answer on the old one
+        Ok(_) | Err(discover::Error::Timeout) => true,
+        Err(err) => {
+            report.failed.push(("AC".to_string(), err.to_string()));
+            false
+        }
+    }
+}
+
+/// Apply `plans` in two rounds: every node gets its ordinary parameters first,
+/// then the network parameters, in the order of the plans so that the
+/// coordinators and the local module are the last to leave the old network.
+pub fn apply(device: &mut DigiMeshDevice, plans: &[NodePlan]) -> Vec<NodeReport> {
+    let mut reports: Vec<NodeReport> = plans
+        .iter()
+        .map(|plan| NodeReport {
+            target: plan.target,
+            node_id: plan.node_id.clone(),
+            changed: Vec::new(),
+            failed: plan.failed.clone(),
+            written: false,
+        })
+        .collect();
+
+    for network_round in [false, true].iter() {
+        for (plan, report) in plans.iter().zip(reports.iter_mut()) {
+            let changes: Vec<Change> = plan
+                .changes
+                .iter()
+                .filter(|change| NodePlan::is_network(change) == *network_round)
+                .cloned()
+                .collect();
+            if changes.is_empty() {
+                continue;
+            }
+            if push_changes(device, plan.target, &changes[..], report) {
+                report.written = true;
+            }
+        }
+    }
+
+    reports
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_literals_and_wildcards() {
+        assert!(glob_match("GATEWAY", "GATEWAY"));
+        assert!(!glob_match("GATEWAY", "GATEWAY2"));
+        assert!(glob_match("SENSOR-*", "SENSOR-"));
+        assert!(glob_match("SENSOR-*", "SENSOR-12"));
+        assert!(glob_match("*-0?", "SENSOR-07"));
+        assert!(!glob_match("*-0?", "SENSOR-007"));
+        assert!(glob_match("*a*b", "xxaxxab"));
+        assert!(!glob_match("*a*b", "xxaxxa"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("?", ""));
+    }
+
+    #[test]
+    fn encode_values() {
+        assert_eq!(ParamValue::Number(0).encode().unwrap(), vec![0]);
+        assert_eq!(
+            ParamValue::Number(0x1f4).encode().unwrap(),
+            vec![0x01, 0xf4]
+        );
+        assert_eq!(
+            ParamValue::Text("0x7FFF".to_string()).encode().unwrap(),
+            vec![0x7f, 0xff]
+        );
+        assert_eq!(
+            ParamValue::Text("0xC".to_string()).encode().unwrap(),
+            vec![0x0c]
+        );
+        assert_eq!(
+            ParamValue::Text("GATEWAY".to_string()).encode().unwrap(),
+            b"GATEWAY".to_vec()
+        );
+        assert!(ParamValue::Text("0xZZ".to_string()).encode().is_err());
+    }
+
+    #[test]
+    fn matches_ignores_leading_zeros_of_numbers() {
+        assert!(ParamValue::Number(0x0c).matches(&[0x00, 0x0c]));
+        assert!(ParamValue::Number(0).matches(&[]));
+        assert!(ParamValue::Text("0x00C".to_string()).matches(&[0x0c]));
+        assert!(!ParamValue::Number(0x0c).matches(&[0x0d]));
+        assert!(ParamValue::Text("NODE".to_string()).matches(b"NODE"));
+        assert!(!ParamValue::Text("NODE".to_string()).matches(b"\0NODE"));
+    }
+
+    #[test]
+    fn later_rules_override_earlier_ones() {
+        let config = FleetConfig::parse(
+            "defaults:\n  ch: 0x0C\n  sm: 0\nnodes:\n  - match: \"SENSOR-*\"\n    params:\n      SM: 8\n  - match: \"SENSOR-1\"\n    params:\n      sm: 0\n      NI: \"S1\"\n",
+        )
+        .unwrap();
+        let desired = config.desired("SENSOR-2");
+        assert_eq!(desired["CH"], ParamValue::Number(0x0c));
+        assert_eq!(desired["SM"], ParamValue::Number(8));
+        assert!(!desired.contains_key("NI"));
+
+        let desired = config.desired("SENSOR-1");
+        assert_eq!(desired["SM"], ParamValue::Number(0));
+        assert_eq!(desired["NI"], ParamValue::Text("S1".to_string()));
+    }
+}
@@ -0,0 +1,315 @@
+#![allow(dead_code)]
+//!
+//! Pin configuration and control of the local module or of a remote node
+//!
+//! DIO10 to DIO14 are configured through P0 to P4, so `Pin::P0` is also the
+//! pin reported as DIO10 in IO samples.
+//!
+
+use crate::api::IoSample;
+use crate::discover::{self, DigiMeshDevice, NodeTarget};
+
+#[derive(Debug)]
+pub enum Error {
+    DeviceError(discover::Error),
+    UnsupportedMode(Pin, PinMode),
+    InvalidPin(String),
+    InvalidResponse(String),
+}
+
+impl From<discover::Error> for Error {
+    fn from(err: discover::Error) -> Self {
+        Error::DeviceError(err)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Error::DeviceError(ref err) => write!(f, "{}", err),
+            Error::UnsupportedMode(pin, mode) => {
+                write!(f, "{:?} is not available on {}", mode, pin.command())
+            }
+            Error::InvalidPin(ref err) => write!(f, "{}", err),
+            Error::InvalidResponse(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pin {
+    D0,
+    D1,
+    D2,
+    D3,
+    D4,
+    D5,
+    D6,
+    D7,
+    D8,
+    D9,
+    P0,
+    P1,
+    P2,
+    P3,
+    P4,
+}
+
+static PINS: [Pin; 15] = [
+    Pin::D0,
+    Pin::D1,
+    Pin::D2,
+    Pin::D3,
+    Pin::D4,
+    Pin::D5,
+    Pin::D6,
+    Pin::D7,
+    Pin::D8,
+    Pin::D9,
+    Pin::P0,
+    Pin::P1,
+    Pin::P2,
+    Pin::P3,
+    Pin::P4,
+];
+
+impl Pin {
+    /// Pin of the line DIO`n` (D10 to D12 are P0 to P2)
+    pub fn from_dio(n: u8) -> Result<Self> {
+        PINS.get(n as usize)
+            .copied()
+            .ok_or_else(|| Error::InvalidPin(format!("No pin DIO{}", n)))
+    }
+
+    pub fn dio(&self) -> u8 {
+        PINS.iter().position(|pin| pin == self).unwrap() as u8
+    }
+
+    /// AT command configuring the pin
+    pub fn command(&self) -> &'static str {
+        [
+            "D0", "D1", "D2", "D3", "D4", "D5", "D6", "D7", "D8", "D9", "P0", "P1", "P2", "P3",
+            "P4",
+        ][self.dio() as usize]
+    }
+
+    /// ADC channel of the pin, if it has one
+    pub fn adc_channel(&self) -> Option<u8> {
+        match *self {
+            Pin::D0 | Pin::D1 | Pin::D2 | Pin::D3 => Some(self.dio()),
+            _ => None,
+        }
+    }
+
+    /// M0/M1 command setting the duty cycle of the pin
+    pub fn pwm_command(&self) -> Option<&'static str> {
+        match *self {
+            Pin::P0 => Some("M0"),
+            Pin::P1 => Some("M1"),
+            _ => None,
+        }
+    }
+
+    /// bit of the pin in the PR pull-up mask
+    pub fn pull_up_bit(&self) -> u8 {
+        match *self {
+            Pin::D4 => 0,
+            Pin::D3 => 1,
+            Pin::D2 => 2,
+            Pin::D1 => 3,
+            Pin::D0 => 4,
+            Pin::D6 => 5,
+            Pin::D8 => 6,
+            Pin::P4 => 7, // DIN
+            Pin::D5 => 8,
+            Pin::D9 => 9,
+            Pin::P2 => 10,
+            Pin::P0 => 11,
+            Pin::P1 => 12,
+            Pin::D7 => 13,
+            Pin::P3 => 14, // DOUT
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PinMode {
+    Disabled,
+    Adc,
+    DigitalIn,
+    OutputLow,
+    OutputHigh,
+    Pwm,
+    Other(u8), // peripheral functions (CTS, RSSI, associate...)
+}
+
+impl PinMode {
+    /// value of the pin command for this mode
+    pub fn code(&self, pin: Pin) -> Result<u8> {
+        match *self {
+            PinMode::Disabled => Ok(0),
+            PinMode::Adc if pin.adc_channel().is_some() => Ok(2),
+            PinMode::Pwm if pin.pwm_command().is_some() => Ok(2),
+            PinMode::DigitalIn => Ok(3),
+            PinMode::OutputLow => Ok(4),
+            PinMode::OutputHigh => Ok(5),
+            PinMode::Other(code) => Ok(code),
+            mode => Err(Error::UnsupportedMode(pin, mode)),
+        }
+    }
+
+    pub fn from_code(pin: Pin, code: u8) -> Self {
+        match code {
+            0 => PinMode::Disabled,
+            2 if pin.adc_channel().is_some() => PinMode::Adc,
+            2 if pin.pwm_command().is_some() => PinMode::Pwm,
+            3 => PinMode::DigitalIn,
+            4 => PinMode::OutputLow,
+            5 => PinMode::OutputHigh,
+            other => PinMode::Other(other),
+        }
+    }
+}
+
+/// big endian value of a command response
+fn response_value(atcmd: &str, data: Option<bytes::BytesMut>) -> Result<u32> {
+    match data {
+        Some(ref data) if !data.is_empty() && data.len() <= 4 => {
+            Ok(data.iter().fold(0u32, |acc, b| (acc << 8) | *b as u32))
+        }
+        _ => Err(Error::InvalidResponse(format!(
+            "Invalid response to {}",
+            atcmd
+        ))),
+    }
+}
+
+/// Pins of the local module or of a remote node
+pub struct Gpio {
+    pub target: NodeTarget,
+}
+
+impl Gpio {
+    pub fn local() -> Self {
+        Self {
+            target: NodeTarget::Local,
+        }
+    }
+
+    pub fn remote(addr: u64) -> Self {
+        Self {
+            target: NodeTarget::Remote(addr),
+        }
+    }
+
+    pub fn set_mode(&self, device: &mut DigiMeshDevice, pin: Pin, mode: PinMode) -> Result<()> {
+        let code = mode.code(pin)?;
+        device.execute(self.target, pin.command(), Some(&[code]))?;
+        Ok(())
+    }
+
+    pub fn mode(&self, device: &mut DigiMeshDevice, pin: Pin) -> Result<PinMode> {
+        let data = device.execute(self.target, pin.command(), None)?;
+        let code = response_value(pin.command(), data)?;
+        Ok(PinMode::from_code(pin, code as u8))
+    }
+
+    /// Configure `pin` as a digital output driven at `high`
+    pub fn set_output(&self, device: &mut DigiMeshDevice, pin: Pin, high: bool) -> Result<()> {
+        let mode = if high {
+            PinMode::OutputHigh
+        } else {
+            PinMode::OutputLow
+        };
+        self.set_mode(device, pin, mode)
+    }
+
+    /// Set the duty cycle of a PWM pin, from 0 to 0x3ff
+    pub fn set_pwm_duty(&self, device: &mut DigiMeshDevice, pin: Pin, duty: u16) -> Result<()> {
+        let atcmd = pin
+            .pwm_command()
+            .ok_or(Error::UnsupportedMode(pin, PinMode::Pwm))?;
+        let duty = duty.min(0x3ff);
+        device.execute(self.target, atcmd, Some(&duty.to_be_bytes()))?;
+        Ok(())
+    }
+
+    pub fn pwm_duty(&self, device: &mut DigiMeshDevice, pin: Pin) -> Result<u16> {
+        let atcmd = pin
+            .pwm_command()
+            .ok_or(Error::UnsupportedMode(pin, PinMode::Pwm))?;
+        let data = device.execute(self.target, atcmd, None)?;
+        Ok(response_value(atcmd, data)? as u16)
+    }
+
+    /// Force a sample of every enabled digital and analog pin (IS)
+    pub fn sample(&self, device: &mut DigiMeshDevice) -> Result<IoSample> {
+        match device.execute(self.target, "IS", None)? {
+            Some(data) => IoSample::parse(&data[..])
+                .map_err(|err| Error::DeviceError(discover::Error::ApiError(err))),
+            None => Err(Error::InvalidResponse(
+                "No pin is enabled for sampling".to_string(),
+            )),
+        }
+    }
+
+    /// Level of a digital input, read with a forced sample
+    pub fn read_input(&self, device: &mut DigiMeshDevice, pin: Pin) -> Result<bool> {
+        self.sample(device)?
+            .digital_state(pin.dio())
+            .ok_or(Error::UnsupportedMode(pin, PinMode::DigitalIn))
+    }
+
+    /// Raw 10-bit reading of an ADC pin, read with a forced sample
+    pub fn read_analog(&self, device: &mut DigiMeshDevice, pin: Pin) -> Result<u16> {
+        let channel = pin
+            .adc_channel()
+            .ok_or(Error::UnsupportedMode(pin, PinMode::Adc))?;
+        self.sample(device)?
+            .analog_value(channel)
+            .ok_or(Error::UnsupportedMode(pin, PinMode::Adc))
+    }
+
+    /// Enable the internal pull-up resistor of `pins` only (PR)
+    pub fn set_pull_ups(&self, device: &mut DigiMeshDevice, pins: &[Pin]) -> Result<()> {
+        let mut mask: u16 = 0;
+        for pin in pins {
+            mask |= 1 << pin.pull_up_bit();
+        }
+        device.execute(self.target, "PR", Some(&mask.to_be_bytes()))?;
+        Ok(())
+    }
+
+    /// Pins with their internal pull-up resistor enabled
+    pub fn pull_ups(&self, device: &mut DigiMeshDevice) -> Result<Vec<Pin>> {
+        let data = device.execute(self.target, "PR", None)?;
+        let mask = response_value("PR", data)?;
+        Ok(PINS
+            .iter()
+            .filter(|pin| mask & (1 << pin.pull_up_bit()) != 0)
+            .copied()
+            .collect())
+    }
+
+    /// Send a sample when one of `pins` changes state (IC), an empty slice
+    /// disables change detection
+    pub fn set_change_detection(&self, device: &mut DigiMeshDevice, pins: &[Pin]) -> Result<()> {
+        let mask = pins.iter().fold(0u16, |acc, pin| acc | 1 << pin.dio());
+        device.execute(self.target, "IC", Some(&mask.to_be_bytes()))?;
+        Ok(())
+    }
+
+    pub fn change_detection(&self, device: &mut DigiMeshDevice) -> Result<Vec<Pin>> {
+        let data = device.execute(self.target, "IC", None)?;
+        let mask = response_value("IC", data)?;
+        Ok(PINS
+            .iter()
+            .filter(|pin| mask & (1 << pin.dio()) != 0)
+            .copied()
+            .collect())
+    }
+}
@@ -0,0 +1,159 @@
+//!
+//! Fleet inventory: remote AT queries run against every discovered node
+//!
+//!
+
+use crate::api::{self, RemoteAtCommandFrame, RemoteCommandOptions};
+use crate::discover::{self, DigiMeshDevice};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Parameters queried on every node
+static INVENTORY_COMMANDS: [&str; 7] = ["VR", "HV", "NI", "PL", "SM", "%V", "TP"];
+
+/// Configuration and health values read from a remote node
+#[derive(Debug, Default, Clone)]
+pub struct NodeInventory {
+    pub power_level: Option<u8>,
+    pub sleep_mode: Option<u8>,
+    pub supply_voltage: Option<u16>, // mV
+    pub temperature: Option<i16>,    // °C
+    pub failed: Vec<String>,         // commands without a valid answer
+}
+
+struct Pending {
+    node: usize,
+    atcmd: &'static str,
+    frame_id: u8,
+    deadline: Instant,
+}
+
+/// Query every discovered node for its firmware and hardware versions, node
+/// identifier, power level, sleep mode, supply voltage and temperature.
+///
+/// At most `concurrency` remote commands are in flight at once and each of
+/// them is given `timeout` to answer.
+pub fn enrich_nodes(
+    device: &mut DigiMeshDevice,
+    concurrency: usize,
+    timeout: Duration,
+) -> discover::Result<()> {
+    let targets: Vec<u64> = match &device.nodes {
+        Some(nodes) => nodes.iter().map(|n| n.addr_64bit).collect(),
+        None => return Ok(()),
+    };
+
+    let mut jobs: VecDeque<(usize, &'static str)> = VecDeque::new();
+    for node in 0..targets.len() {
+        for atcmd in INVENTORY_COMMANDS.iter() {
+            jobs.push_back((node, *atcmd));
+        }
+    }
+
+    device.reinitialize_if_reset()?;
+    let mut results: Vec<NodeInventory> = vec![NodeInventory::default(); targets.len()];
+    let mut in_flight: Vec<Pending> = Vec::new();
+    let concurrency = concurrency.max(1);
+
+    while !jobs.is_empty() || !in_flight.is_empty() {
+        while in_flight.len() < concurrency {
+            let (node, atcmd) = match jobs.pop_front() {
+                Some(job) => job,
+                None => break,
+            };
+            let frame_id = device.write_frame(&RemoteAtCommandFrame {
+                dest_addr: targets[node],
+                options: &RemoteCommandOptions {
+                    apply_changes: false,
+                },
+                atcmd,
+                cmd_param: None,
+            })?;
+            in_flight.push(Pending {
+                node,
+                atcmd,
+                frame_id,
+                deadline: Instant::now() + timeout,
+            });
+        }
+
+        let deadline = in_flight.iter().map(|p| p.deadline).min().unwrap();
+        match device.next_frame(deadline)? {
+            Some(frame) => {
+                let resp = match frame.downcast_ref::<api::RemoteAtCommandResponse>() {
+                    Some(resp) => resp,
+                    None => continue,
+                };
+                let pos = in_flight.iter().position(|p| {
+                    p.frame_id == resp.frame_id()
+                        && targets[p.node] == resp.dest_addr
+                        && p.atcmd.as_bytes() == &resp.at_command[..]
+                });
+                if let Some(pos) = pos {
+                    let pending = in_flight.remove(pos);
+                    let data = match resp.command_data {
+                        Some(ref data) if resp.status() == api::AtStatus::Ok => Some(&data[..]),
+                        _ => None,
+                    };
+                    apply(
+                        device,
+                        &mut results[pending.node],
+                        pending.node,
+                        pending.atcmd,
+                        data,
+                    );
+                }
+            }
+            None => {
+                let now = Instant::now();
+                let (expired, waiting): (Vec<Pending>, Vec<Pending>) =
+                    in_flight.into_iter().partition(|p| p.deadline <= now);
+                for pending in expired {
+                    results[pending.node].failed.push(pending.atcmd.to_string());
+                }
+                in_flight = waiting;
+            }
+        }
+    }
+
+    if let Some(nodes) = device.nodes.as_mut() {
+        for (node, inventory) in nodes.iter_mut().zip(results) {
+            node.inventory = Some(inventory);
+        }
+    }
+
+    Ok(())
+}
+
+fn apply(
+    device: &mut DigiMeshDevice,
+    inventory: &mut NodeInventory,
+    node: usize,
+    atcmd: &str,
+    data: Option<&[u8]>,
+) {
+    let data = match data {
+        Some(data) if !data.is_empty() => data,
+        _ => {
+            inventory.failed.push(atcmd.to_string());
+            return;
+        }
+    };
+    let value = data.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+    let remote = &mut device.nodes.as_mut().unwrap()[node];
+
+    match atcmd {
+        "VR" => remote.firmware_version = Some(value as u16),
+        "HV" => remote.hardware_version = Some(value as u16),
+        "NI" => {
+            if let Ok(node_id) = std::str::from_utf8(data) {
+                remote.node_id = node_id.to_string();
+            }
+        }
+        "PL" => inventory.power_level = Some(value as u8),
+        "SM" => inventory.sleep_mode = Some(value as u8),
+        "%V" => inventory.supply_voltage = Some(value as u16),
+        "TP" => inventory.temperature = Some(value as u16 as i16),
+        _ => {}
+    }
+}
@@ -0,0 +1,174 @@
+#![allow(dead_code)]
+//!
+//! Link quality measurement: RSSI (DB) sampling and transmit test loops
+//!
+//! DB holds the RSSI of the last packet received, so it is sampled after each
+//! packet received from a node, and is the strength of the last hop of that
+//! packet. It is not read after a transmit test: it would then report the
+//! acknowledgement of the first hop rather than the target.
+//!
+
+use crate::api::{self, TransmitRequestFrame};
+use crate::discover::{self, DigiMeshDevice};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+static TEST_PATTERN: &[u8] = b"LINKTEST";
+
+/// Running RSSI statistics, values in -dBm
+#[derive(Debug, Default, Clone)]
+pub struct RssiStats {
+    samples: Vec<u8>,
+}
+
+impl RssiStats {
+    pub fn push(&mut self, rssi: u8) {
+        self.samples.push(rssi);
+    }
+
+    pub fn count(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// strongest sample (smallest -dBm magnitude)
+    pub fn max(&self) -> Option<u8> {
+        self.samples.iter().min().copied()
+    }
+
+    /// weakest sample (largest -dBm magnitude)
+    pub fn min(&self) -> Option<u8> {
+        self.samples.iter().max().copied()
+    }
+
+    pub fn avg(&self) -> Option<f64> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let sum: u32 = self.samples.iter().map(|s| *s as u32).sum();
+        Some(sum as f64 / self.samples.len() as f64)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct LinkReport {
+    pub rssi: RssiStats,
+    pub sent: usize,
+    pub delivered: usize,
+    pub retries: u32,
+}
+
+impl LinkReport {
+    /// packet delivery ratio between 0 and 1
+    pub fn delivery_ratio(&self) -> Option<f64> {
+        if self.sent == 0 {
+            return None;
+        }
+        Some(self.delivered as f64 / self.sent as f64)
+    }
+
+    /// average transmit retries over the delivered packets
+    pub fn avg_retries(&self) -> Option<f64> {
+        if self.delivered == 0 {
+            return None;
+        }
+        Some(self.retries as f64 / self.delivered as f64)
+    }
+}
+
+/// Per node link quality reports, keyed by 64-bit address
+#[derive(Debug, Default)]
+pub struct LinkMonitor {
+    reports: BTreeMap<u64, LinkReport>,
+}
+
+impl LinkMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn reports(&self) -> &BTreeMap<u64, LinkReport> {
+        &self.reports
+    }
+
+    pub fn report(&self, addr: u64) -> Option<&LinkReport> {
+        self.reports.get(&addr)
+    }
+
+    pub fn record_rssi(&mut self, addr: u64, rssi: u8) {
+        self.reports.entry(addr).or_default().rssi.push(rssi);
+    }
+
+    pub fn record_transmit(&mut self, addr: u64, delivered: bool, retries: u8) {
+        let report = self.reports.entry(addr).or_default();
+        report.sent += 1;
+        if delivered {
+            report.delivered += 1;
+            report.retries += retries as u32;
+        }
+    }
+
+    /// Read DB right after a packet from `addr` was received, and return it
+    pub fn sample(&mut self, device: &mut DigiMeshDevice, addr: u64) -> discover::Result<u8> {
+        let rssi = device.get_rssi()?;
+        self.record_rssi(addr, rssi);
+        Ok(rssi)
+    }
+
+    /// Send `count` test packets of `payload_size` bytes to `addr`, `batch` at a
+    /// time, and account for each transmit status. Packets without a status
+    /// before `timeout` are counted as lost.
+    pub fn test_link(
+        &mut self,
+        device: &mut DigiMeshDevice,
+        addr: u64,
+        count: usize,
+        batch: usize,
+        payload_size: usize,
+        timeout: Duration,
+    ) -> discover::Result<()> {
+        let payload: Vec<u8> = TEST_PATTERN
+            .iter()
+            .cycle()
+            .take(payload_size)
+            .copied()
+            .collect();
+        let batch = batch.max(1);
+
+        let mut remaining = count;
+        while remaining > 0 {
+            let size = remaining.min(batch);
+            // all packets of a batch go to the same node, so two packets sharing a
+            // frame id are still accounted for correctly
+            device.reinitialize_if_reset()?;
+            let mut pending: Vec<u8> = Vec::with_capacity(size);
+            for _ in 0..size {
+                pending.push(device.write_frame(&TransmitRequestFrame {
+                    dest_addr: addr,
+                    broadcast_radius: 0,
+                    options: None,
+                    payload: &payload[..],
+                })?);
+            }
+
+            let deadline = Instant::now() + timeout;
+            while !pending.is_empty() {
+                let frame = match device.next_frame(deadline)? {
+                    Some(frame) => frame,
+                    None => break,
+                };
+                if let Some(status) = frame.downcast_ref::<api::TransmitStatus>() {
+                    if let Some(pos) = pending.iter().position(|id| *id == status.frame_id()) {
+                        pending.remove(pos);
+                        self.record_transmit(addr, status.delivered(), status.retry_count());
+                    }
+                }
+            }
+            for _ in pending.iter() {
+                self.record_transmit(addr, false, 0);
+            }
+            remaining -= size;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,514 @@
+#![allow(dead_code)]
+//!
+//! Sensor data logger: periodic IO sampling of remote nodes written as a time series
+//!
+//! The logger sets IR (sample period) and IC (change detection) on the chosen
+//! nodes, then turns every IO sample received from them into one record per
+//! channel, calibrated and written to CSV or NDJSON files. A new file is
+//! started when the current one reaches its size limit or when the UTC day
+//! changes, depending on the rollover settings.
+//!
+
+use crate::api::{IoDataSample, DEFAULT_VREF_MV};
+use crate::discover::{self, DigiMeshDevice, NodeTarget};
+use crate::gpio::{self, Gpio, Pin};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug)]
+pub enum Error {
+    DeviceError(discover::Error),
+    GpioError(gpio::Error),
+    IOError(std::io::Error),
+    ConfigError(String),
+}
+
+impl From<discover::Error> for Error {
+    fn from(err: discover::Error) -> Self {
+        Error::DeviceError(err)
+    }
+}
+
+impl From<gpio::Error> for Error {
+    fn from(err: gpio::Error) -> Self {
+        Error::GpioError(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::IOError(err)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Error::DeviceError(ref err) => write!(f, "{}", err),
+            Error::GpioError(ref err) => write!(f, "{}", err),
+            Error::IOError(ref err) => write!(f, "{}", err),
+            Error::ConfigError(ref err) => write!(f, "Invalid logger configuration: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Linear conversion of the raw reading of a channel: `raw * scale + offset`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Calibration {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    #[serde(default)]
+    pub offset: f64,
+    #[serde(default)]
+    pub unit: String,
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Csv,
+    Ndjson,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Rollover {
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    #[serde(default)]
+    pub daily: bool,
+}
+
+/// `logger` section of config.json
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoggerConfig {
+    /// node identifiers, or 64-bit addresses written with 16 hex digits
+    pub nodes: Vec<String>,
+    /// IR in milliseconds, 0 leaves periodic sampling disabled
+    #[serde(default)]
+    pub sample_rate_ms: u16,
+    /// DIO lines sending a sample when they change state
+    #[serde(default)]
+    pub change_detect: Vec<u8>,
+    pub format: LogFormat,
+    /// file name prefix, the date and file index are appended
+    pub path: String,
+    #[serde(default)]
+    pub rollover: Rollover,
+    /// calibration by channel: AD0 to AD3, DIO0 to DIO14 and VCC
+    #[serde(default)]
+    pub channels: HashMap<String, Calibration>,
+}
+
+impl LoggerConfig {
+    pub fn from_json(v: &serde_json::Value) -> Result<Self> {
+        serde_json::from_value(v.clone()).map_err(|err| Error::ConfigError(err.to_string()))
+    }
+}
+
+/// One calibrated reading of one channel
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub timestamp_ms: u64,
+    pub node: u64,
+    pub channel: String,
+    pub name: Option<String>,
+    pub raw: u16,
+    pub value: f64,
+    pub unit: String,
+}
+
+static CSV_HEADER: &str = "time,timestamp_ms,node_address,channel,name,raw,value,unit";
+
+impl Record {
+    fn to_csv(&self) -> String {
+        format!(
+            "{},{},{:x},{},{},{},{},{}",
+            format_utc(self.timestamp_ms),
+            self.timestamp_ms,
+            self.node,
+            self.channel,
+            csv_field(self.name.as_deref().unwrap_or("")),
+            self.raw,
+            self.value,
+            csv_field(&self.unit)
+        )
+    }
+
+    fn to_ndjson(&self) -> String {
+        json!({
+            "time": format_utc(self.timestamp_ms),
+            "timestamp_ms": self.timestamp_ms,
+            "node_address": format!("{:x}", self.node),
+            "channel": self.channel,
+            "name": self.name,
+            "raw": self.raw,
+            "value": self.value,
+            "unit": self.unit,
+        })
+        .to_string()
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(',') || field.contains('"') || field.contains('\n') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// (year, month, day) of a count of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn format_date(timestamp_ms: u64) -> String {
+    let (year, month, day) = civil_from_days((timestamp_ms / 86_400_000) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// ISO 8601 UTC time
+fn format_utc(timestamp_ms: u64) -> String {
+    let secs = (timestamp_ms / 1000) % 86_400;
+    format!(
+        "{}T{:02}:{:02}:{:02}.{:03}Z",
+        format_date(timestamp_ms),
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        timestamp_ms % 1000
+    )
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Time series files with rollover by size or by day
+pub struct RecordWriter {
+    format: LogFormat,
+    prefix: String,
+    rollover: Rollover,
+    file: Option<File>,
+    written: u64,
+    day: String,
+    index: u32,
+}
+
+impl RecordWriter {
+    pub fn new(format: LogFormat, prefix: &str, rollover: Rollover) -> Self {
+        Self {
+            format,
+            prefix: prefix.to_string(),
+            rollover,
+            file: None,
+            written: 0,
+            day: String::new(),
+            index: 0,
+        }
+    }
+
+    fn file_name(&self) -> String {
+        let ext = match self.format {
+            LogFormat::Csv => "csv",
+            LogFormat::Ndjson => "ndjson",
+        };
+        if self.rollover.daily {
+            format!("{}_{}_{}.{}", self.prefix, self.day, self.index, ext)
+        } else {
+            format!("{}_{}.{}", self.prefix, self.index, ext)
+        }
+    }
+
+    fn open(&mut self) -> Result<()> {
+        // never overwrite the files of a previous run
+        let mut file = loop {
+            match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(self.file_name())
+            {
+                Ok(file) => break file,
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => self.index += 1,
+                Err(err) => return Err(Error::IOError(err)),
+            }
+        };
+        self.written = 0;
+        if self.format == LogFormat::Csv {
+            writeln!(file, "{}", CSV_HEADER)?;
+            self.written += CSV_HEADER.len() as u64 + 1;
+        }
+        self.file = Some(file);
+        Ok(())
+    }
+
+    pub fn write(&mut self, record: &Record) -> Result<()> {
+        let line = match self.format {
+            LogFormat::Csv => record.to_csv(),
+            LogFormat::Ndjson => record.to_ndjson(),
+        };
+
+        let day = format_date(record.timestamp_ms);
+        if self.rollover.daily && day != self.day {
+            self.day = day;
+            self.index = 0;
+            self.file = None;
+        }
+        if let Some(max_bytes) = self.rollover.max_bytes {
+            if self.file.is_some() && self.written + line.len() as u64 + 1 > max_bytes {
+                self.index += 1;
+                self.file = None;
+            }
+        }
+        if self.file.is_none() {
+            self.open()?;
+        }
+
+        let file = self.file.as_mut().unwrap();
+        writeln!(file, "{}", line)?;
+        self.written += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Logger {
+    pub config: LoggerConfig,
+    nodes: Vec<u64>,
+    writer: RecordWriter,
+}
+
+impl Logger {
+    /// Resolve the configured nodes against the discovered ones. A node not
+    /// discovered is only accepted as a 64-bit address of 16 hex digits.
+    pub fn new(device: &DigiMeshDevice, config: LoggerConfig) -> Result<Self> {
+        let mut nodes = Vec::new();
+        for node in &config.nodes {
+            let discovered = device
+                .nodes
+                .iter()
+                .flatten()
+                .find(|n| n.node_id == *node)
+                .map(|n| n.addr_64bit);
+            let addr = match u64::from_str_radix(node, 16) {
+                Ok(addr) if node.len() == 16 => Some(addr),
+                _ => None,
+            };
+            match discovered.or(addr) {
+                Some(addr) => nodes.push(addr),
+                None => {
+                    return Err(Error::DeviceError(discover::Error::UnknownNode(
+                        node.clone(),
+                    )))
+                }
+            }
+        }
+        for dio in &config.change_detect {
+            Pin::from_dio(*dio)?;
+        }
+
+        let writer = RecordWriter::new(config.format, &config.path, config.rollover.clone());
+        Ok(Self {
+            config,
+            nodes,
+            writer,
+        })
+    }
+
+    /// Set the sample period and the change detection of every node
+    pub fn configure(&self, device: &mut DigiMeshDevice) -> Result<()> {
+        let pins = self
+            .config
+            .change_detect
+            .iter()
+            .map(|dio| Pin::from_dio(*dio))
+            .collect::<gpio::Result<Vec<Pin>>>()?;
+        for addr in &self.nodes {
+            device.execute(
+                NodeTarget::Remote(*addr),
+                "IR",
+                Some(&self.config.sample_rate_ms.to_be_bytes()),
+            )?;
+            Gpio::remote(*addr).set_change_detection(device, &pins)?;
+        }
+        Ok(())
+    }
+
+    /// Stop the periodic sampling and change detection of every node
+    pub fn disable(&self, device: &mut DigiMeshDevice) -> Result<()> {
+        for addr in &self.nodes {
+            device.execute(NodeTarget::Remote(*addr), "IR", Some(&[0, 0]))?;
+            Gpio::remote(*addr).set_change_detection(device, &[])?;
+        }
+        Ok(())
+    }
+
+    fn calibrate(&self, channel: String, raw: u16, default: (f64, &str)) -> Record {
+        let (name, value, unit) = match self.config.channels.get(&channel) {
+            Some(cal) => (
+                cal.name.clone(),
+                raw as f64 * cal.scale + cal.offset,
+                cal.unit.clone(),
+            ),
+            None => (None, default.0, default.1.to_string()),
+        };
+        Record {
+            timestamp_ms: 0,
+            node: 0,
+            channel,
+            name,
+            raw,
+            value,
+            unit,
+        }
+    }
+
+    /// Records of every channel of a sample. Uncalibrated analog channels are
+    /// converted to millivolts.
+    pub fn records(&self, sample: &IoDataSample, timestamp_ms: u64) -> Vec<Record> {
+        let io = &sample.sample;
+        let mut records = Vec::new();
+
+        for dio in 0..16 {
+            if let Some(state) = io.digital_state(dio) {
+                records.push(self.calibrate(
+                    format!("DIO{}", dio),
+                    state as u16,
+                    (state as u8 as f64, ""),
+                ));
+            }
+        }
+        for (channel, raw) in &io.analog {
+            let mv = io.analog_millivolts(*channel, DEFAULT_VREF_MV).unwrap_or(0);
+            records.push(self.calibrate(format!("AD{}", channel), *raw, (mv as f64, "mV")));
+        }
+        if let Some(raw) = io.supply_voltage {
+            records.push(self.calibrate("VCC".to_string(), raw, (raw as f64, "mV")));
+        }
+
+        for record in records.iter_mut() {
+            record.timestamp_ms = timestamp_ms;
+            record.node = sample.source_addr;
+        }
+        records
+    }
+
+    /// Log the samples of the configured nodes for `duration`, returning the
+    /// number of records written
+    pub fn run(&mut self, device: &mut DigiMeshDevice, duration: Duration) -> Result<usize> {
+        let start_time = Instant::now();
+        let mut written = 0;
+
+        while let Some(remaining) = duration.checked_sub(start_time.elapsed()) {
+            let sample = match device.recv_io_sample(remaining)? {
+                Some(sample) => sample,
+                None => break,
+            };
+            if !self.nodes.contains(&sample.source_addr) {
+                continue;
+            }
+            for record in self.records(&sample, now_ms()) {
+                self.writer.write(&record)?;
+                written += 1;
+            }
+            self.writer.flush()?;
+        }
+
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn record(timestamp_ms: u64) -> Record {
+        Record {
+            timestamp_ms,
+            node: 0x0013a20040a1b2c3,
+            channel: "AD0".to_string(),
+            name: Some("temp, outside".to_string()),
+            raw: 512,
+            value: 21.5,
+            unit: "°C".to_string(),
+        }
+    }
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(20_088), (2024, 12, 31));
+        assert_eq!(format_utc(1_709_210_096_789), "2024-02-29T12:34:56.789Z");
+    }
+
+    #[test]
+    fn csv_line() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(
+            record(0).to_csv(),
+            "1970-01-01T00:00:00.000Z,0,13a20040a1b2c3,AD0,\"temp, outside\",512,21.5,°C"
+        );
+    }
+
+    #[test]
+    fn rollover_by_size_and_day() {
+        let dir = std::env::temp_dir().join(format!("logger-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let prefix = dir.join("log").to_str().unwrap().to_string();
+        let day = 19_782 * 86_400_000;
+        let line_len = record(day).to_csv().len() as u64 + 1;
+
+        let rollover = Rollover {
+            max_bytes: Some(CSV_HEADER.len() as u64 + 1 + 2 * line_len),
+            daily: true,
+        };
+        let mut writer = RecordWriter::new(LogFormat::Csv, &prefix, rollover);
+        for timestamp_ms in [day, day + 1, day + 2, day + 86_400_000] {
+            writer.write(&record(timestamp_ms)).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let lines = |name: &str| fs::read_to_string(dir.join(name)).unwrap().lines().count();
+        assert_eq!(lines("log_2024-02-29_0.csv"), 3);
+        assert_eq!(lines("log_2024-02-29_1.csv"), 2);
+        assert_eq!(lines("log_2024-03-01_0.csv"), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
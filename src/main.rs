@@ -1,9 +1,28 @@
 mod api; 
 mod discover;
+mod export;
+mod filetransfer;
+mod fleet;
+mod gpio;
+mod inventory;
+mod link;
+mod logger;
+mod oui;
+mod profile;
+mod rpc;
+mod security;
+mod sleep;
+mod topology;
+mod transparent;
+mod transport;
 use serde_json::{json, Value};
 use std::io::{Write, Read};
 use std::time::{Duration};
 use std::fs::File;
+use std::path::Path;
+
+static PORT: &str = "/dev/ttyUSB0";
+static BAUD_RATE: u32 = 9600;
 
 pub async fn run_xbee_script() -> Result<bool, Box<dyn std::error::Error>> {
     // Open the file in read-only mode with buffer.
@@ -15,10 +34,13 @@ pub async fn run_xbee_script() -> Result<bool, Box<dyn std::error::Error>> {
     // Parse the string of data into serde_json::Value.
     let v: Value = serde_json::from_str(&contents).expect("Cannot parse JSON");
     
-    let port = "/dev/ttyUSB0";
-    let baud_rate = 9600;
-
-    let mut xbee_device = match discover::DigiMeshDevice::new(port, baud_rate) {
+    // les modules neufs sont en mode transparent
+    let opened = if v["onboard"].as_bool().unwrap_or(false) {
+        discover::DigiMeshDevice::onboard(PORT, BAUD_RATE)
+    } else {
+        discover::DigiMeshDevice::new(PORT, BAUD_RATE)
+    };
+    let mut xbee_device = match opened {
         Ok(device) => device,
         Err(err) => {
             println!("Erreur lors de la création de l'appareil XBee : {}", err);
@@ -50,12 +72,15 @@ pub async fn run_xbee_script() -> Result<bool, Box<dyn std::error::Error>> {
         println!("Exécution d'un scan instantané...");
         match xbee_device.discover_nodes(Some(Duration::from_secs(5))) {
             Ok(_) => {
+                enrich_nodes(&mut xbee_device, &v);
+                resolve_vendors(&mut xbee_device, &v);
                 if let Some(nodes) = &xbee_device.nodes {
                     if nodes.is_empty() {
                         write_empty_json().unwrap();
                         return Ok(false);
                     } else {
                         write_nodes_to_json(nodes).unwrap();
+                        report_network(&mut xbee_device, &v)?;
                         return Ok(true);
                     }
                 } else {
@@ -79,14 +104,21 @@ pub async fn run_xbee_script() -> Result<bool, Box<dyn std::error::Error>> {
         }
         
         println!("Début du scan de {}s...", scan_duration.as_secs());
+        xbee_device.learn_sleep = v["learn_sleep"].as_bool().unwrap_or(false);
         match xbee_device.scheduled_discover_nodes(Duration::from_secs(scan_duration.as_secs())) {
             Ok(_) => {
+                let scan_end = std::time::Instant::now();
+                apply_sleep_cycles(&mut xbee_device, &v);
+                enrich_nodes(&mut xbee_device, &v);
+                resolve_vendors(&mut xbee_device, &v);
                 if let Some(nodes) = &xbee_device.nodes {
                     if nodes.is_empty() {
                         write_empty_json().unwrap();
                         return Ok(false);
                     } else {
-                        write_schedulednodes_to_json(nodes)?; 
+                        let margin = Duration::from_secs(v["absence_margin"].as_u64().unwrap_or(30));
+                        write_schedulednodes_to_json(nodes, margin, scan_end)?;
+                        report_network(&mut xbee_device, &v)?;
                         return Ok(true);
                     }
                 } else {
@@ -103,15 +135,469 @@ pub async fn run_xbee_script() -> Result<bool, Box<dyn std::error::Error>> {
     }
 }
 
+/// Cycles de sommeil configurés dans `sleep_cycles` (identifiant ou adresse -> secondes),
+/// prioritaires sur ceux lus sur les noeuds
+fn apply_sleep_cycles(xbee_device: &mut discover::DigiMeshDevice, v: &Value) {
+    let cycles = match v["sleep_cycles"].as_object() {
+        Some(cycles) => cycles,
+        None => return,
+    };
+    for node in xbee_device.nodes.iter_mut().flatten() {
+        let configured = cycles.get(&node.node_id).or_else(|| cycles.get(&format!("{:x}", node.addr_64bit)));
+        if let Some(secs) = configured.and_then(|c| c.as_u64()) {
+            node.sleep_cycle = if secs > 0 { Some(Duration::from_secs(secs)) } else { None };
+        }
+    }
+}
+
+fn enrich_nodes(xbee_device: &mut discover::DigiMeshDevice, v: &Value) {
+    if !v["enrich_nodes"].as_bool().unwrap_or(false) {
+        return;
+    }
+
+    let concurrency = v["enrich_concurrency"].as_u64().unwrap_or(4) as usize;
+    let timeout = Duration::from_secs(v["enrich_timeout"].as_u64().unwrap_or(5));
+    println!("Interrogation des noeuds distants...");
+    if let Err(err) = inventory::enrich_nodes(xbee_device, concurrency, timeout) {
+        println!("Erreur lors de l'interrogation des noeuds : {}", err);
+    }
+}
+
+fn resolve_vendors(xbee_device: &mut discover::DigiMeshDevice, v: &Value) {
+    // a newer registry file can override the one embedded in the binary
+    let database = match v["oui_registry"].as_str() {
+        Some(path) => match oui::OuiDatabase::load(path) {
+            Ok(database) => database,
+            Err(err) => {
+                println!("Erreur lors du chargement du registre OUI {} : {}", path, err);
+                oui::OuiDatabase::embedded()
+            }
+        },
+        None => oui::OuiDatabase::embedded(),
+    };
+
+    for node in xbee_device.nodes.iter_mut().flatten() {
+        node.vendor = database.lookup(node.addr_64bit).map(String::from);
+    }
+}
+
+fn node_to_json(node: &discover::RemoteDigiMeshDevice, durations: Value) -> Value {
+    let mut node_data = json!({
+        "node_id": node.node_id,
+        "node_address": format!("{:x}", node.addr_64bit),
+        "vendor": node.vendor,
+        "zigbee_durations": durations,
+    });
+
+    if let Some(inventory) = &node.inventory {
+        node_data["firmware_version"] = json!(node.firmware_version.map(|v| format!("{:x}", v)));
+        node_data["hardware_version"] = json!(node.hardware_version.map(|v| format!("{:x}", v)));
+        node_data["power_level"] = json!(inventory.power_level);
+        node_data["sleep_mode"] = json!(inventory.sleep_mode);
+        node_data["supply_voltage_mv"] = json!(inventory.supply_voltage);
+        node_data["temperature_c"] = json!(inventory.temperature);
+        node_data["failed_queries"] = json!(inventory.failed);
+    }
+
+    node_data
+}
+
+/// `send-file <adresse 64 bits> <fichier>` et `receive-file [dossier] [timeout]`
+pub fn run_file_command(args: &[String]) -> Result<bool, Box<dyn std::error::Error>> {
+    let opts = filetransfer::TransferOptions::default();
+
+    match args[1].as_str() {
+        "send-file" => {
+            if args.len() < 4 {
+                println!("Usage : {} send-file <adresse 64 bits> <fichier>", args[0]);
+                return Ok(false);
+            }
+            let addr = u64::from_str_radix(&args[2], 16)?;
+            let mut xbee_device = discover::DigiMeshDevice::new(PORT, BAUD_RATE)?;
+
+            println!("Envoi de {} vers {:x}...", args[3], addr);
+            match filetransfer::send_file(&mut xbee_device, addr, Path::new(&args[3]), &opts) {
+                Ok(_) => {
+                    println!("Fichier envoyé et vérifié.");
+                    Ok(true)
+                }
+                Err(err) => {
+                    println!("Erreur lors de l'envoi du fichier : {}", err);
+                    Ok(false)
+                }
+            }
+        }
+        _ => {
+            let dir = args.get(2).map(|d| d.as_str()).unwrap_or(".");
+            let timeout = match args.get(3) {
+                Some(t) => t.parse::<u64>()?,
+                None => 300,
+            };
+            let mut xbee_device = discover::DigiMeshDevice::new(PORT, BAUD_RATE)?;
+
+            println!("En attente d'un fichier pendant {}s...", timeout);
+            match filetransfer::receive_file(&mut xbee_device, Path::new(dir), Duration::from_secs(timeout), &opts) {
+                Ok(path) => {
+                    println!("Fichier reçu et vérifié : {}", path.display());
+                    Ok(true)
+                }
+                Err(err) => {
+                    println!("Erreur lors de la réception du fichier : {}", err);
+                    Ok(false)
+                }
+            }
+        }
+    }
+}
+
+/// `remote-at <adresse 64 bits | NI> <commande> [valeur hex]`
+pub fn run_remote_at_command(args: &[String]) -> Result<bool, Box<dyn std::error::Error>> {
+    if args.len() < 4 {
+        println!("Usage : {} remote-at <adresse 64 bits | NI> <commande> [valeur hex]", args[0]);
+        return Ok(false);
+    }
+    let node = match u64::from_str_radix(&args[2], 16) {
+        Ok(addr) if args[2].len() == 16 => discover::RemoteNode::Addr(addr),
+        _ => discover::RemoteNode::NodeId(args[2].clone()),
+    };
+    let atcmd = args[3].to_uppercase();
+    let mut xbee_device = discover::DigiMeshDevice::new(PORT, BAUD_RATE)?;
+
+    let result = match args.get(4) {
+        Some(value) => {
+            let value = if value.len() % 2 == 1 { format!("0{}", value) } else { value.clone() };
+            let value = (0..value.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&value[i..i + 2], 16))
+                .collect::<Result<Vec<u8>, _>>()?;
+            xbee_device
+                .remote_set(node.clone(), &atcmd, &value)
+                .and_then(|_| xbee_device.remote_apply(node))
+                .map(|_| println!("{} appliqué.", atcmd))
+        }
+        None => xbee_device.remote_get(node, &atcmd).map(|data| {
+            println!("{} = {}", atcmd, data.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+        }),
+    };
+
+    match result {
+        Ok(_) => Ok(true),
+        Err(err) => {
+            println!("Erreur lors de la commande {} : {}", atcmd, err);
+            Ok(false)
+        }
+    }
+}
+
+/// `push-config <fichier.yaml> [--dry-run]`
+pub fn run_push_config(args: &[String]) -> Result<bool, Box<dyn std::error::Error>> {
+    if args.len() < 3 {
+        println!("Usage : {} push-config <fichier.yaml> [--dry-run]", args[0]);
+        return Ok(false);
+    }
+    let dry_run = args.iter().any(|a| a == "--dry-run");
+    let config = fleet::FleetConfig::load(&args[2])?;
+    let mut xbee_device = discover::DigiMeshDevice::new(PORT, BAUD_RATE)?;
+
+    println!("Découverte des noeuds...");
+    xbee_device.discover_nodes(Some(Duration::from_secs(5)))?;
+
+    println!("Lecture de la configuration des noeuds...");
+    let plans = fleet::plan(&mut xbee_device, &config)?;
+
+    let node_name = |target: &discover::NodeTarget| match target {
+        discover::NodeTarget::Local => "local".to_string(),
+        discover::NodeTarget::Remote(addr) => format!("{:x}", addr),
+    };
+    let change_to_json = |change: &fleet::Change| {
+        json!({
+            "param": change.param,
+            "old": change.old,
+            // never echo a network key in the report
+            "new": if change.param == "KY" { "********".to_string() } else { change.new_display() },
+        })
+    };
+
+    let mut data = serde_json::Map::new();
+    if dry_run {
+        for plan in &plans {
+            data.insert(node_name(&plan.target), json!({
+                "node_id": plan.node_id,
+                "changes": plan.changes.iter().map(&change_to_json).collect::<Vec<_>>(),
+                "failed": plan.failed.iter().map(|(p, e)| json!({"param": p, "error": e})).collect::<Vec<_>>(),
+            }));
+        }
+    } else {
+        println!("Application de la configuration...");
+        for report in fleet::apply(&mut xbee_device, &plans) {
+            data.insert(node_name(&report.target), json!({
+                "node_id": report.node_id,
+                "changed": report.changed.iter().map(&change_to_json).collect::<Vec<_>>(),
+                "failed": report.failed.iter().map(|(p, e)| json!({"param": p, "error": e})).collect::<Vec<_>>(),
+                "written": report.written,
+            }));
+        }
+    }
+
+    let json_data = serde_json::to_string_pretty(&data)?;
+    println!("{}", json_data);
+
+    let mut file = File::create("xbee_fleet_report.json")?;
+    file.write_all(json_data.as_bytes())?;
+
+    Ok(true)
+}
+
+/// `local`, une adresse 64 bits en hexadécimal ou un identifiant de noeud
+fn parse_node_target(xbee_device: &mut discover::DigiMeshDevice, node: &str) -> Result<discover::NodeTarget, discover::Error> {
+    if node == "local" {
+        return Ok(discover::NodeTarget::Local);
+    }
+    let node = match u64::from_str_radix(node, 16) {
+        Ok(addr) if node.len() == 16 => discover::RemoteNode::Addr(addr),
+        _ => discover::RemoteNode::NodeId(node.to_string()),
+    };
+    Ok(discover::NodeTarget::Remote(xbee_device.resolve_node(&node)?))
+}
+
+/// `profile save <local | adresse 64 bits | NI> <fichier>`,
+/// `profile restore <local | adresse 64 bits | NI> <fichier> [--keep-ni]` et
+/// `profile diff <fichier> <fichier | local | adresse 64 bits | NI>`
+pub fn run_profile_command(args: &[String]) -> Result<bool, Box<dyn std::error::Error>> {
+    if args.len() < 5 {
+        println!("Usage : {} profile save|restore|diff <noeud | fichier> <fichier | noeud>", args[0]);
+        return Ok(false);
+    }
+
+    match args[2].as_str() {
+        "save" => {
+            let mut xbee_device = discover::DigiMeshDevice::new(PORT, BAUD_RATE)?;
+            let target = parse_node_target(&mut xbee_device, &args[3])?;
+            println!("Lecture de la configuration de {}...", args[3]);
+            let profile = profile::Profile::read(&mut xbee_device, target)?;
+            profile.save(&args[4])?;
+            println!("{} paramètres sauvegardés dans {}", profile.params.len(), args[4]);
+            Ok(true)
+        }
+        "restore" => {
+            let profile = profile::Profile::load(&args[4])?;
+            let mut xbee_device = discover::DigiMeshDevice::new(PORT, BAUD_RATE)?;
+            let target = parse_node_target(&mut xbee_device, &args[3])?;
+            let skip: &[&str] = if args.iter().any(|a| a == "--keep-ni") { &["NI"] } else { &[] };
+            println!("Restauration de {} sur {}...", args[4], args[3]);
+            let report = profile.restore(&mut xbee_device, target, skip)?;
+            println!("{} paramètres restaurés, {} ignorés", report.restored.len(), report.skipped.len());
+            for (atcmd, err) in &report.failed {
+                println!("Erreur lors de la restauration de {} : {}", atcmd, err);
+            }
+            Ok(report.failed.is_empty())
+        }
+        "diff" => {
+            let left = profile::Profile::load(&args[3])?;
+            let diffs = if Path::new(&args[4]).is_file() {
+                left.diff(&profile::Profile::load(&args[4])?)
+            } else {
+                let mut xbee_device = discover::DigiMeshDevice::new(PORT, BAUD_RATE)?;
+                let target = parse_node_target(&mut xbee_device, &args[4])?;
+                left.diff_live(&mut xbee_device, target)?
+            };
+            for diff in &diffs {
+                println!("{} : {} -> {}", diff.param, diff.left.as_deref().unwrap_or("-"), diff.right.as_deref().unwrap_or("-"));
+            }
+            println!("{} différence(s)", diffs.len());
+            Ok(true)
+        }
+        other => {
+            println!("Commande de profil inconnue : {}", other);
+            Ok(false)
+        }
+    }
+}
+
+/// `transparent-at <commande> [valeur hex]` pour les modules en mode transparent (AP=0)
+pub fn run_transparent_command(args: &[String]) -> Result<bool, Box<dyn std::error::Error>> {
+    if args.len() < 3 {
+        println!("Usage : {} transparent-at <commande> [valeur hex]", args[0]);
+        return Ok(false);
+    }
+    let atcmd = args[2].to_uppercase();
+    let mut xbee_device = discover::DigiMeshDevice::open(PORT, BAUD_RATE)?;
+    let mut session = transparent::CommandSession::enter(&mut xbee_device)?;
+
+    if atcmd == "ND" {
+        let nodes = session.discover()?;
+        for node in &nodes {
+            println!("{:x} {} ({})", node.addr_64bit, node.node_id, node.role.name());
+        }
+        println!("{} noeud(s) découvert(s)", nodes.len());
+    } else {
+        let resp = session.command(&atcmd, args.get(3).map(|v| v.as_str()))?;
+        println!("{} = {}", atcmd, resp);
+    }
+    session.exit()?;
+    Ok(true)
+}
+
+/// `set-mode api|transparent` ; le mode API échappé (AP=2) est refusé
+pub fn run_set_mode(args: &[String]) -> Result<bool, Box<dyn std::error::Error>> {
+    let mode = match args.get(2).map(|m| m.as_str()) {
+        Some("api") => transparent::ApiMode::Api,
+        Some("transparent") => transparent::ApiMode::Transparent,
+        Some("api-escaped") => {
+            println!("Le mode API échappé (AP=2) n'est pas pris en charge : les trames ne sont pas échappées.");
+            return Ok(false);
+        }
+        _ => {
+            println!("Usage : {} set-mode api|transparent", args[0]);
+            return Ok(false);
+        }
+    };
+    let mut xbee_device = discover::DigiMeshDevice::open(PORT, BAUD_RATE)?;
+
+    println!("Passage du module en mode {:?}...", mode);
+    match transparent::enable_api_mode(&mut xbee_device, mode) {
+        Ok(_) => {
+            println!("Mode {:?} activé et sauvegardé.", mode);
+            Ok(true)
+        }
+        Err(err) => {
+            println!("Erreur lors du changement de mode : {}", err);
+            Ok(false)
+        }
+    }
+}
+
+/// `sleep <noeud> [mode période_ms éveil_ms [options]]` lit ou règle SM, SP, ST et SO
+pub fn run_sleep_command(args: &[String]) -> Result<bool, Box<dyn std::error::Error>> {
+    if args.len() != 3 && args.len() < 6 {
+        println!("Usage : {} sleep <noeud> [mode période_ms éveil_ms [options]]", args[0]);
+        return Ok(false);
+    }
+    let mut xbee_device = discover::DigiMeshDevice::new(PORT, BAUD_RATE)?;
+    let target = parse_node_target(&mut xbee_device, &args[2])?;
+
+    if args.len() >= 6 {
+        let config = sleep::SleepConfig {
+            mode: sleep::SleepMode::from_code(args[3].parse()?),
+            sleep_period: Duration::from_millis(args[4].parse()?),
+            wake_time: Duration::from_millis(args[5].parse()?),
+            options: match args.get(6) {
+                Some(options) => u16::from_str_radix(options.trim_start_matches("0x"), 16)?,
+                None => 0,
+            },
+        };
+        sleep::write_sleep_config(&mut xbee_device, target, &config)?;
+        println!("Paramètres de sommeil appliqués à {}", args[2]);
+    }
+
+    let config = sleep::read_sleep_config(&mut xbee_device, target)?;
+    println!("Mode : {:?} (SM={})", config.mode, config.mode.code());
+    println!("Sommeil : {} ms, éveil : {} ms, options : 0x{:x}", config.sleep_period.as_millis(), config.wake_time.as_millis(), config.options);
+    Ok(true)
+}
+
+/// `security status|disable`, `security enable <fichier clé>`, `security rotate <fichier clé>`
+/// et `security generate-key <fichier clé>`. `enable` génère et enregistre une nouvelle clé quand le
+/// fichier n'existe pas encore. La clé n'est jamais affichée ni écrite dans le rapport.
+pub fn run_security_command(args: &[String]) -> Result<bool, Box<dyn std::error::Error>> {
+    let usage = || println!("Usage : {} security status|disable|enable|rotate|generate-key [fichier clé]", args[0]);
+    let command = match args.get(2) {
+        Some(command) => command.as_str(),
+        None => {
+            usage();
+            return Ok(false);
+        }
+    };
+
+    if command == "generate-key" {
+        match args.get(3) {
+            Some(path) => {
+                security::NetworkKey::generate().save(path)?;
+                println!("Nouvelle clé réseau enregistrée dans {}", path);
+                return Ok(true);
+            }
+            None => {
+                usage();
+                return Ok(false);
+            }
+        }
+    }
+    let key = match (command, args.get(3)) {
+        ("rotate", None) | ("enable", None) => {
+            usage();
+            return Ok(false);
+        }
+        ("enable", Some(path)) if !std::path::Path::new(path).exists() => {
+            let key = security::NetworkKey::generate();
+            key.save(path)?;
+            println!("Nouvelle clé réseau enregistrée dans {}", path);
+            Some(key)
+        }
+        ("rotate", Some(path)) | ("enable", Some(path)) => Some(security::NetworkKey::load(path)?),
+        _ => None,
+    };
+
+    let mut xbee_device = discover::DigiMeshDevice::new(PORT, BAUD_RATE)?;
+    println!("Découverte des noeuds...");
+    xbee_device.discover_nodes(Some(Duration::from_secs(5)))?;
+
+    let result = match command {
+        "status" => security::verify(&mut xbee_device),
+        "enable" => security::enable_encryption(&mut xbee_device, key.as_ref().unwrap()),
+        "disable" => security::disable_encryption(&mut xbee_device),
+        "rotate" => security::rotate_key(&mut xbee_device, key.as_ref().unwrap()),
+        _ => {
+            usage();
+            return Ok(false);
+        }
+    };
+    let (nodes, success) = match result {
+        Ok(nodes) => {
+            let success = nodes.iter().all(|n| n.error.is_none());
+            (nodes, success)
+        }
+        Err(security::Error::StagingFailed(nodes)) => {
+            println!("Changement annulé : tous les noeuds ne l'ont pas accepté, rien n'a été appliqué.");
+            (nodes, false)
+        }
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let mut data = serde_json::Map::new();
+    for node in &nodes {
+        let name = match node.target {
+            discover::NodeTarget::Local => "local".to_string(),
+            discover::NodeTarget::Remote(addr) => format!("{:x}", addr),
+        };
+        let encrypted = match node.encrypted {
+            Some(true) => "chiffré",
+            Some(false) => "non chiffré",
+            None if node.unconfirmed => "injoignable, AC non confirmé",
+            None => "injoignable",
+        };
+        println!("{} ({}) : {}{}", name, node.node_id, encrypted, node.error.as_ref().map(|e| format!(" - {}", e)).unwrap_or_default());
+        data.insert(name, json!({
+            "node_id": node.node_id,
+            "encrypted": node.encrypted,
+            "staged": node.staged,
+            "applied": node.applied,
+            "unconfirmed": node.unconfirmed,
+            "written": node.written,
+            "error": node.error,
+        }));
+    }
+
+    let json_data = serde_json::to_string_pretty(&data)?;
+    File::create("xbee_security_report.json")?.write_all(json_data.as_bytes())?;
+    println!("Rapport écrit dans xbee_security_report.json");
+    Ok(success)
+}
+
 fn write_nodes_to_json(nodes: &[discover::RemoteDigiMeshDevice]) -> std::io::Result<()> {
     let mut data = serde_json::Map::new();
 
     for (index, node) in nodes.iter().enumerate() {
-        let node_data = json!({
-            "node_id": node.node_id,
-            "node_address": format!("{:x}", node.addr_64bit),
-            "zigbee_durations": "none",
-        });
+        let node_data = node_to_json(node, json!("none"));
         data.insert((index + 1).to_string(), node_data);
     }
 
@@ -124,7 +610,7 @@ fn write_nodes_to_json(nodes: &[discover::RemoteDigiMeshDevice]) -> std::io::Res
     Ok(())
 }
 
-fn write_schedulednodes_to_json(nodes: &[discover::RemoteDigiMeshDevice]) -> std::io::Result<()> {
+fn write_schedulednodes_to_json(nodes: &[discover::RemoteDigiMeshDevice], margin: Duration, scan_end: std::time::Instant) -> std::io::Result<()> {
     let mut data = serde_json::Map::new();
 
     for (index, node) in nodes.iter().enumerate() {
@@ -132,11 +618,18 @@ fn write_schedulednodes_to_json(nodes: &[discover::RemoteDigiMeshDevice]) -> std
             format!("{}-{}", start.elapsed().as_secs(), end.elapsed().as_secs())
         }).collect();
 
-        let node_data = json!({
-            "node_id": node.node_id,
-            "node_address": format!("{:x}", node.addr_64bit),
-            "zigbee_durations": durations_data,
-        });
+        let mut node_data = node_to_json(node, json!(durations_data));
+
+        // un noeud endormi n'est absent qu'après son cycle de sommeil plus la marge
+        let presence = sleep::presence(&node.durations, node.sleep_cycle, margin, scan_end);
+        node_data["sleep_cycle_s"] = json!(node.sleep_cycle.map(|c| c.as_secs()));
+        node_data["presence"] = json!(presence.spans.iter().map(|(start, end)| {
+            format!("{}-{}", start.elapsed().as_secs(), end.elapsed().as_secs())
+        }).collect::<Vec<_>>());
+        node_data["absences"] = json!(presence.absences.iter().map(|absence| json!({
+            "period": format!("{}-{}", absence.start.elapsed().as_secs(), absence.end.elapsed().as_secs()),
+            "reason": absence.reason,
+        })).collect::<Vec<_>>());
         data.insert(index.to_string(), node_data);
     }
 
@@ -149,6 +642,218 @@ fn write_schedulednodes_to_json(nodes: &[discover::RemoteDigiMeshDevice]) -> std
     Ok(())
 }
 
+fn report_network(xbee_device: &mut discover::DigiMeshDevice, v: &Value) -> std::io::Result<()> {
+    let mut mesh = None;
+    if v["map_topology"].as_bool().unwrap_or(false) {
+        let neighbor_timeout = Duration::from_secs(v["neighbor_timeout"].as_u64().unwrap_or(10));
+        println!("Cartographie du réseau maillé...");
+        match topology::survey(xbee_device, neighbor_timeout) {
+            Ok(topology) => {
+                write_topology_to_json(&topology)?;
+                mesh = Some(topology);
+            }
+            Err(err) => println!("Erreur lors de la cartographie du réseau : {}", err),
+        }
+    }
+
+    if v["trace_route"].as_bool().unwrap_or(false) {
+        trace_routes(xbee_device)?;
+    }
+
+    // RSSI des paquets reçus pendant l'écoute, livraison des tests de lien
+    let mut monitor = link::LinkMonitor::new();
+    if v["link_test"].is_object() {
+        test_links(xbee_device, &mut monitor, &v["link_test"]);
+    }
+
+    if let Some(duration) = v["listen_duration"].as_u64() {
+        listen(xbee_device, &mut monitor, Duration::from_secs(duration))?;
+    }
+
+    if !monitor.reports().is_empty() {
+        write_link_quality_to_json(&monitor)?;
+    }
+
+    if v["logger"].is_object() {
+        log_samples(xbee_device, &v["logger"]);
+    }
+
+    if v["export_graph"].as_bool().unwrap_or(false) {
+        let local = (xbee_device.addr_64bit.unwrap_or(0), xbee_device.node_id.clone().unwrap_or_default());
+        let role = xbee_device.get_role().unwrap_or(discover::DeviceRole::Unknown);
+        let nodes = xbee_device.nodes.as_deref().unwrap_or(&[]);
+        let graph = export::Graph::new((local.0, &local.1, role), nodes, mesh.as_ref());
+
+        File::create("xbee_topology.dot")?.write_all(graph.to_dot().as_bytes())?;
+        File::create("xbee_topology.graphml")?.write_all(graph.to_graphml().as_bytes())?;
+        println!("Graphe exporté vers xbee_topology.dot et xbee_topology.graphml");
+    }
+
+    Ok(())
+}
+
+fn listen(xbee_device: &mut discover::DigiMeshDevice, monitor: &mut link::LinkMonitor, duration: Duration) -> std::io::Result<()> {
+    println!("Écoute des données pendant {}s...", duration.as_secs());
+    let start_time = std::time::Instant::now();
+    let mut packets = Vec::new();
+
+    while let Some(remaining) = duration.checked_sub(start_time.elapsed()) {
+        match xbee_device.recv_data(remaining) {
+            Ok(Some(packet)) => {
+                // DB donne le RSSI du dernier paquet reçu, à lire tout de suite
+                let rssi = monitor.sample(xbee_device, packet.source_addr).ok();
+                packets.push(json!({
+                    "node_address": format!("{:x}", packet.source_addr),
+                    "broadcast": packet.options.broadcast,
+                    "received_after": start_time.elapsed().as_secs(),
+                    "rssi": rssi.map(|r| -(r as i16)),
+                    "data": packet.data.iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+                }));
+            }
+            Ok(None) => break,
+            Err(err) => {
+                println!("Erreur lors de la réception des données : {}", err);
+                break;
+            }
+        }
+    }
+
+    let json_data = serde_json::to_string_pretty(&packets)?;
+    println!("{}", json_data);
+
+    let mut file = File::create("xbee_received.json")?;
+    file.write_all(json_data.as_bytes())?;
+
+    Ok(())
+}
+
+fn log_samples(xbee_device: &mut discover::DigiMeshDevice, v: &Value) {
+    let duration = Duration::from_secs(v["duration"].as_u64().unwrap_or(60));
+    let mut data_logger = match logger::LoggerConfig::from_json(v)
+        .and_then(|config| logger::Logger::new(xbee_device, config))
+    {
+        Ok(data_logger) => data_logger,
+        Err(err) => {
+            println!("Erreur lors de la configuration de l'enregistreur : {}", err);
+            return;
+        }
+    };
+
+    println!("Configuration de l'échantillonnage des noeuds...");
+    if let Err(err) = data_logger.configure(xbee_device) {
+        println!("Erreur lors de la configuration de l'échantillonnage : {}", err);
+        return;
+    }
+
+    println!("Enregistrement des mesures pendant {}s...", duration.as_secs());
+    match data_logger.run(xbee_device, duration) {
+        Ok(count) => println!("{} mesures enregistrées dans {}", count, data_logger.config.path),
+        Err(err) => println!("Erreur lors de l'enregistrement des mesures : {}", err),
+    }
+}
+
+fn trace_routes(xbee_device: &mut discover::DigiMeshDevice) -> std::io::Result<()> {
+    let addrs: Vec<u64> = xbee_device.nodes.iter().flatten().map(|n| n.addr_64bit).collect();
+    let mut data = serde_json::Map::new();
+
+    for addr in addrs {
+        match xbee_device.trace_route(addr, Duration::from_secs(5)) {
+            Ok(hops) => {
+                let hops_data: Vec<_> = hops.iter().map(|hop| {
+                    json!({
+                        "source": format!("{:x}", hop.source_addr),
+                        "responder": format!("{:x}", hop.responder_addr),
+                        "receiver": format!("{:x}", hop.receiver_addr),
+                        "timestamp": hop.timestamp,
+                    })
+                }).collect();
+                data.insert(format!("{:x}", addr), json!(hops_data));
+            }
+            Err(err) => println!("Erreur lors du traçage de la route vers {:x} : {}", addr, err),
+        }
+    }
+
+    let json_data = serde_json::to_string_pretty(&data)?;
+    println!("{}", json_data);
+
+    let mut file = File::create("xbee_routes.json")?;
+    file.write_all(json_data.as_bytes())?;
+
+    Ok(())
+}
+
+fn test_links(xbee_device: &mut discover::DigiMeshDevice, monitor: &mut link::LinkMonitor, v: &Value) {
+    let count = v["count"].as_u64().unwrap_or(10) as usize;
+    let batch = v["batch"].as_u64().unwrap_or(1) as usize;
+    let payload_size = v["payload_size"].as_u64().unwrap_or(32) as usize;
+    let timeout = Duration::from_secs(v["timeout"].as_u64().unwrap_or(5));
+
+    let addrs: Vec<u64> = xbee_device.nodes.iter().flatten().map(|n| n.addr_64bit).collect();
+    for addr in addrs {
+        println!("Test du lien vers {:x}...", addr);
+        if let Err(err) = monitor.test_link(xbee_device, addr, count, batch, payload_size, timeout) {
+            println!("Erreur lors du test du lien vers {:x} : {}", addr, err);
+        }
+    }
+}
+
+fn write_link_quality_to_json(monitor: &link::LinkMonitor) -> std::io::Result<()> {
+    let mut data = serde_json::Map::new();
+    for (addr, report) in monitor.reports() {
+        let node_data = json!({
+            "rssi_min": report.rssi.min().map(|r| -(r as i16)),
+            "rssi_avg": report.rssi.avg().map(|r| -r),
+            "rssi_max": report.rssi.max().map(|r| -(r as i16)),
+            "sent": report.sent,
+            "delivered": report.delivered,
+            "delivery_ratio": report.delivery_ratio(),
+            "avg_retries": report.avg_retries(),
+        });
+        data.insert(format!("{:x}", addr), node_data);
+    }
+
+    let json_data = serde_json::to_string_pretty(&data)?;
+    println!("{}", json_data);
+
+    let mut file = File::create("xbee_linkquality.json")?;
+    file.write_all(json_data.as_bytes())?;
+
+    Ok(())
+}
+
+fn write_topology_to_json(topology: &topology::Topology) -> std::io::Result<()> {
+    let nodes: Vec<_> = topology.nodes().map(|(addr, node_id)| {
+        json!({
+            "node_id": node_id,
+            "node_address": format!("{:x}", addr),
+            "neighbors": topology.neighbors(addr).iter().map(|(n, _)| format!("{:x}", n)).collect::<Vec<_>>(),
+        })
+    }).collect();
+
+    let links: Vec<_> = topology.links().iter().map(|link| {
+        json!({
+            "from": format!("{:x}", link.a),
+            "to": format!("{:x}", link.b),
+            "rssi": link.rssi.map(|r| -(r as i16)),
+        })
+    }).collect();
+
+    let articulation_points: Vec<_> = topology.articulation_points().iter().map(|addr| format!("{:x}", addr)).collect();
+
+    let data = json!({
+        "nodes": nodes,
+        "links": links,
+        "articulation_points": articulation_points,
+    });
+
+    let json_data = serde_json::to_string_pretty(&data)?;
+    println!("{}", json_data);
+
+    let mut file = File::create("xbee_topology.json")?;
+    file.write_all(json_data.as_bytes())?;
+
+    Ok(())
+}
 
 fn write_empty_json() -> std::io::Result<()> {
     let empty_data = serde_json::Map::new();
@@ -163,7 +868,20 @@ fn write_empty_json() -> std::io::Result<()> {
 
 #[tokio::main]
 async fn main() {
-    match run_xbee_script().await {
+    let args: Vec<String> = std::env::args().collect();
+    let result = match args.get(1).map(|a| a.as_str()) {
+        Some("send-file") | Some("receive-file") => run_file_command(&args),
+        Some("remote-at") => run_remote_at_command(&args),
+        Some("push-config") => run_push_config(&args),
+        Some("profile") => run_profile_command(&args),
+        Some("transparent-at") => run_transparent_command(&args),
+        Some("set-mode") => run_set_mode(&args),
+        Some("sleep") => run_sleep_command(&args),
+        Some("security") => run_security_command(&args),
+        _ => run_xbee_script().await,
+    };
+
+    match result {
         Ok(success) => {
             if success {
                 println!("Script executed successfully.");
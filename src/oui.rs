@@ -0,0 +1,89 @@
+//!
+//! Manufacturer lookup of 64-bit addresses from the IEEE OUI registry
+//!
+//! The registry bundled in `src/database/oui.csv` is compiled into the binary
+//! by the build script; a newer registry file can still be loaded at runtime.
+//!
+
+#[path = "database/registry.rs"]
+mod registry;
+
+use std::fs::File;
+use std::io::Read;
+
+// OUI_ORGS and OUI_PREFIXES, sorted by prefix length then prefix
+include!(concat!(env!("OUT_DIR"), "/oui_table.rs"));
+
+pub struct OuiDatabase {
+    loaded: Option<registry::PrefixTable>,
+}
+
+impl OuiDatabase {
+    /// Registry embedded at build time
+    pub fn embedded() -> Self {
+        Self { loaded: None }
+    }
+
+    /// Registry read from a CSV file in the IEEE format, replacing the embedded one
+    pub fn load(path: &str) -> std::io::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        Ok(Self::parse(&contents))
+    }
+
+    pub fn parse(contents: &str) -> Self {
+        let table = registry::build_table(registry::parse_registry(contents));
+        Self {
+            loaded: Some(table),
+        }
+    }
+
+    fn prefixes(&self) -> &[(u8, u64, u32)] {
+        match &self.loaded {
+            Some((prefixes, _)) => &prefixes[..],
+            None => OUI_PREFIXES,
+        }
+    }
+
+    fn org(&self, idx: u32) -> &str {
+        match &self.loaded {
+            Some((_, orgs)) => &orgs[idx as usize],
+            None => OUI_ORGS[idx as usize],
+        }
+    }
+
+    /// Organization owning the longest registered prefix of `addr`
+    pub fn lookup(&self, addr: u64) -> Option<&str> {
+        let prefixes = self.prefixes();
+        for (_, bits) in registry::REGISTRIES.iter() {
+            let key = (*bits, addr >> (64 - *bits as u32));
+            if let Ok(pos) = prefixes.binary_search_by_key(&key, |(b, p, _)| (*b, *p)) {
+                return Some(self.org(prefixes[pos].2));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_prefers_the_longest_prefix() {
+        let database = OuiDatabase::parse(
+            "Registry,Assignment,Organization Name,Organization Address\n\
+             MA-L,0013A2,Digi International Inc,\n\
+             MA-M,0013A2F,Medium Co,\n\
+             MA-S,0013A2F12,Small Co,\n",
+        );
+        assert_eq!(
+            database.lookup(0x0013a20040a1b2c3),
+            Some("Digi International Inc")
+        );
+        assert_eq!(database.lookup(0x0013a2f000000000), Some("Medium Co"));
+        assert_eq!(database.lookup(0x0013a2f123456789), Some("Small Co"));
+        assert_eq!(database.lookup(0x0013a30000000000), None);
+    }
+}
@@ -0,0 +1,442 @@
+#![allow(dead_code)]
+//!
+//! Configuration profiles: backup, restore and diff of the AT parameters of a module
+//!
+//! Profiles are stored as versioned JSON. The `profile.xml` found inside an
+//! XCTU `.xpro` archive can also be read and written; the archive itself
+//! (firmware files and zip container) is left to XCTU.
+//!
+
+use crate::discover::{self, DigiMeshDevice, NodeTarget};
+use crate::fleet::NETWORK_PARAMS;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub static PROFILE_VERSION: u32 = 1;
+
+/// Readable and writable parameters saved in a profile. Those a firmware
+/// does not support are left out of the profile.
+#[rustfmt::skip]
+static PROFILE_PARAMS: [&str; 64] = [
+    // networking
+    "ID", "CH", "HP", "CM", "MT", "RR", "MR", "NH", "BH", "NN", "NQ", "CE", "NO", "CI", "DE",
+    "SE", "TO", "DH", "DL", "NT", "PL", "EE",
+    // addressing and api
+    "NI", "AO", "AP", "BD", "NB", "SB", "RO", "FT",
+    // io
+    "D0", "D1", "D2", "D3", "D4", "D5", "D6", "D7", "D8", "D9", "P0", "P1", "P2", "P3", "P4",
+    "PR", "PD", "M0", "M1", "IR", "IC", "LT", "RP", "AV",
+    // sleep
+    "SM", "SP", "ST", "SO", "WH", "PO",
+    // command mode and misc
+    "DD", "CT", "GT", "CC",
+];
+
+/// Parameters changing the serial link to the local module, never restored on it
+static SERIAL_PARAMS: [&str; 6] = ["AP", "BD", "NB", "SB", "RO", "FT"];
+
+#[derive(Debug)]
+pub enum Error {
+    DeviceError(discover::Error),
+    IOError(std::io::Error),
+    EncodeError(serde_json::Error),
+    FormatError(String),
+}
+
+impl From<discover::Error> for Error {
+    fn from(err: discover::Error) -> Self {
+        Error::DeviceError(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::IOError(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::EncodeError(err)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Error::DeviceError(ref err) => write!(f, "{}", err),
+            Error::IOError(ref err) => write!(f, "{}", err),
+            Error::EncodeError(ref err) => write!(f, "{}", err),
+            Error::FormatError(ref err) => write!(f, "Invalid profile: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    let hex = if hex.len() % 2 == 1 {
+        format!("0{}", hex)
+    } else {
+        hex.to_string()
+    };
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<std::result::Result<Vec<u8>, _>>()
+        .map_err(|_| Error::FormatError(format!("Invalid hex value {}", hex)))
+}
+
+/// numeric values are compared without their leading zeros
+fn normalize(hex: &str) -> String {
+    let trimmed = hex.trim_start_matches('0');
+    if trimmed.is_empty() {
+        "0".to_string()
+    } else {
+        trimmed.to_uppercase()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    pub version: u32,
+    pub created: u64, // seconds since the epoch
+    pub node_id: Option<String>,
+    pub addr_64bit: Option<String>,
+    pub firmware_version: Option<String>,
+    pub hardware_version: Option<String>,
+    /// values in hex, except NI which is text
+    pub params: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamDiff {
+    pub param: String,
+    pub left: Option<String>,
+    pub right: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RestoreReport {
+    pub restored: Vec<String>,
+    pub skipped: Vec<String>,
+    pub failed: Vec<(String, String)>,
+}
+
+impl Profile {
+    /// Read every profile parameter supported by the module
+    pub fn read(device: &mut DigiMeshDevice, target: NodeTarget) -> Result<Self> {
+        let mut identity =
+            |atcmd: &str| device.get_param(target, atcmd).ok().map(|v| to_hex(&v[..]));
+        let addr_64bit = match (identity("SH"), identity("SL")) {
+            (Some(sh), Some(sl)) => Some(format!("{:0>8}{:0>8}", sh, sl)),
+            _ => None,
+        };
+        let firmware_version = identity("VR");
+        let hardware_version = identity("HV");
+
+        let mut params = BTreeMap::new();
+        for atcmd in PROFILE_PARAMS.iter() {
+            match device.get_param(target, atcmd) {
+                Ok(value) if *atcmd == "NI" => {
+                    params.insert(
+                        atcmd.to_string(),
+                        String::from_utf8_lossy(&value[..]).to_string(),
+                    );
+                }
+                Ok(value) => {
+                    params.insert(atcmd.to_string(), to_hex(&value[..]));
+                }
+                // not supported by this firmware
+                Err(discover::Error::CommandError(_, _)) => {}
+                Err(err) => return Err(Error::DeviceError(err)),
+            }
+        }
+
+        Ok(Self {
+            version: PROFILE_VERSION,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            node_id: params.get("NI").cloned(),
+            addr_64bit,
+            firmware_version,
+            hardware_version,
+            params,
+        })
+    }
+
+    /// Load a JSON profile, or an XCTU profile.xml when the file ends in .xml
+    pub fn load(path: &str) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        if path.to_lowercase().ends_with(".xml") {
+            return Self::from_xml(&contents);
+        }
+        let profile: Self = serde_json::from_str(&contents)?;
+        if profile.version > PROFILE_VERSION {
+            return Err(Error::FormatError(format!(
+                "Unsupported profile version {}",
+                profile.version
+            )));
+        }
+        Ok(profile)
+    }
+
+    /// Save as JSON, or as an XCTU profile.xml when the path ends in .xml
+    pub fn save(&self, path: &str) -> Result<()> {
+        let contents = if path.to_lowercase().ends_with(".xml") {
+            self.to_xml()
+        } else {
+            serde_json::to_string_pretty(self)?
+        };
+        File::create(path)?.write_all(contents.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn to_xml(&self) -> String {
+        let mut xml =
+            String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<data>\n  <profile>\n");
+        xml.push_str("    <settings>\n");
+        for (atcmd, value) in &self.params {
+            let value = if atcmd == "NI" {
+                xml_escape(value)
+            } else {
+                normalize(value)
+            };
+            xml.push_str(&format!(
+                "      <setting command=\"{}\">{}</setting>\n",
+                atcmd, value
+            ));
+        }
+        xml.push_str("    </settings>\n  </profile>\n</data>\n");
+        xml
+    }
+
+    /// Read the settings of an XCTU profile.xml
+    pub fn from_xml(contents: &str) -> Result<Self> {
+        let mut params = BTreeMap::new();
+        let mut rest = contents;
+        while let Some(start) = rest.find("<setting ") {
+            rest = &rest[start..];
+            let end = rest
+                .find("</setting>")
+                .ok_or_else(|| Error::FormatError("Unterminated setting".to_string()))?;
+            let element = &rest[..end];
+            rest = &rest[end..];
+
+            let command = element
+                .split("command=\"")
+                .nth(1)
+                .and_then(|s| s.split('"').next())
+                .ok_or_else(|| Error::FormatError("Setting without command".to_string()))?;
+            let value = match element.find('>') {
+                Some(idx) => xml_unescape(element[idx + 1..].trim()),
+                None => String::new(),
+            };
+            params.insert(command.to_uppercase(), value);
+        }
+        if params.is_empty() {
+            return Err(Error::FormatError("No setting found".to_string()));
+        }
+
+        Ok(Self {
+            version: PROFILE_VERSION,
+            created: 0,
+            node_id: params.get("NI").cloned(),
+            addr_64bit: None,
+            firmware_version: None,
+            hardware_version: None,
+            params,
+        })
+    }
+
+    /// Parameters whose value differ between `self` (left) and `other` (right)
+    pub fn diff(&self, other: &Profile) -> Vec<ParamDiff> {
+        let mut params: Vec<&String> = self.params.keys().chain(other.params.keys()).collect();
+        params.sort();
+        params.dedup();
+
+        params
+            .into_iter()
+            .filter_map(|param| {
+                let left = self.params.get(param);
+                let right = other.params.get(param);
+                let same = match (left, right) {
+                    (Some(l), Some(r)) if param == "NI" => l == r,
+                    (Some(l), Some(r)) => normalize(l) == normalize(r),
+                    _ => false,
+                };
+                if same {
+                    return None;
+                }
+                Some(ParamDiff {
+                    param: param.clone(),
+                    left: left.cloned(),
+                    right: right.cloned(),
+                })
+            })
+            .collect()
+    }
+
+    /// Compare the profile with the live configuration of a module
+    pub fn diff_live(
+        &self,
+        device: &mut DigiMeshDevice,
+        target: NodeTarget,
+    ) -> Result<Vec<ParamDiff>> {
+        let live = Self::read(device, target)?;
+        Ok(self.diff(&live))
+    }
+
+    /// Queue the profile on a module, network parameters last, then save it
+    /// with WR and apply it with AC. The local module applies its queued values
+    /// with the WR frame, all at once. `skip` lists parameters to leave as they
+    /// are, such as NI when cloning a module.
+    pub fn restore(
+        &self,
+        device: &mut DigiMeshDevice,
+        target: NodeTarget,
+        skip: &[&str],
+    ) -> Result<RestoreReport> {
+        let mut report = RestoreReport::default();
+        let mut params: Vec<(&String, &String)> = self.params.iter().collect();
+        params.sort_by_key(|(atcmd, _)| NETWORK_PARAMS.contains(&atcmd.as_str()));
+
+        for (atcmd, value) in params {
+            let local_serial =
+                target == NodeTarget::Local && SERIAL_PARAMS.contains(&atcmd.as_str());
+            if skip.contains(&atcmd.as_str()) || local_serial {
+                report.skipped.push(atcmd.clone());
+                continue;
+            }
+            let bytes = if atcmd == "NI" {
+                value.as_bytes().to_vec()
+            } else {
+                from_hex(value)?
+            };
+            match device.queue_param(target, atcmd, &bytes[..]) {
+                Ok(_) => report.restored.push(atcmd.clone()),
+                Err(err) => report.failed.push((atcmd.clone(), err.to_string())),
+            }
+        }
+
+        match target {
+            NodeTarget::Local => {
+                device.execute(target, "WR", None)?;
+                device.execute(target, "AC", None)?;
+            }
+            NodeTarget::Remote(addr) => {
+                device.remote_write(addr)?;
+                match device.remote_apply(addr) {
+                    // the node may have moved to the restored network
+                    Ok(_) | Err(discover::Error::Timeout) => {}
+                    Err(err) => return Err(Error::DeviceError(err)),
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn xml_unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(params: &[(&str, &str)]) -> Profile {
+        Profile {
+            version: PROFILE_VERSION,
+            created: 0,
+            node_id: None,
+            addr_64bit: None,
+            firmware_version: None,
+            hardware_version: None,
+            params: params
+                .iter()
+                .map(|(atcmd, value)| (atcmd.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn hex_values() {
+        assert_eq!(to_hex(&[0x00, 0x7f, 0xff]), "007FFF");
+        assert_eq!(from_hex("7FFF").unwrap(), vec![0x7f, 0xff]);
+        assert_eq!(from_hex("C").unwrap(), vec![0x0c]);
+        assert!(from_hex("0G").is_err());
+        assert_eq!(normalize("000c"), "C");
+        assert_eq!(normalize("0000"), "0");
+    }
+
+    #[test]
+    fn diff_compares_numbers_without_leading_zeros() {
+        let left = profile(&[("CH", "0C"), ("ID", "7FFF"), ("NI", "A"), ("SM", "0")]);
+        let right = profile(&[("CH", "000C"), ("ID", "7FFE"), ("NI", "a"), ("SP", "1F4")]);
+        let diff = left.diff(&right);
+        let changed: Vec<&str> = diff.iter().map(|d| &d.param[..]).collect();
+        assert_eq!(changed, vec!["ID", "NI", "SM", "SP"]);
+        assert_eq!(
+            diff[2],
+            ParamDiff {
+                param: "SM".to_string(),
+                left: Some("0".to_string()),
+                right: None,
+            }
+        );
+        assert!(left.diff(&left).is_empty());
+    }
+
+    #[test]
+    fn xml_round_trip() {
+        let original = profile(&[("CH", "000C"), ("NI", "<A & \"B\">")]);
+        let xml = original.to_xml();
+        assert!(xml.contains("<setting command=\"CH\">C</setting>"));
+        assert!(xml.contains("&lt;A &amp; &quot;B&quot;&gt;"));
+
+        let parsed = Profile::from_xml(&xml).unwrap();
+        assert!(parsed.diff(&original).is_empty());
+        assert_eq!(parsed.node_id.as_deref(), Some("<A & \"B\">"));
+    }
+
+    #[test]
+    fn from_xml_reads_xctu_settings() {
+        let parsed = Profile::from_xml(
+            "<data><profile><settings>\n\
+             <setting command=\"id\"> 7FFF </setting>\n\
+             <setting command=\"NI\">it&apos;s</setting>\n\
+             </settings></profile></data>",
+        )
+        .unwrap();
+        assert_eq!(parsed.params["ID"], "7FFF");
+        assert_eq!(parsed.params["NI"], "it's");
+
+        assert!(Profile::from_xml("<data></data>").is_err());
+        assert!(Profile::from_xml("<setting command=\"CH\">C").is_err());
+    }
+}
@@ -0,0 +1,312 @@
+#![allow(dead_code)]
+//!
+//! Request/response RPC between nodes running this crate
+//!
+//! Requests and responses are JSON documents carried by the fragmenting
+//! transport, so bodies are not limited by NP. A message is the magic byte
+//! followed by the JSON encoding of `RpcMessage`.
+//!
+
+use crate::discover::{self, DigiMeshDevice};
+use crate::transport::Transport;
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+static MAGIC: u8 = 0xf9;
+/// responses remembered to answer retried requests without running them twice
+static REPLAY_LEN: usize = 32;
+
+#[derive(Debug)]
+pub enum Error {
+    DeviceError(discover::Error),
+    EncodeError(serde_json::Error),
+    RemoteError(String),
+    UnknownMethod(String),
+    Timeout,
+}
+
+impl From<discover::Error> for Error {
+    fn from(err: discover::Error) -> Self {
+        Error::DeviceError(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::EncodeError(err)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Error::DeviceError(ref err) => write!(f, "{}", err),
+            Error::EncodeError(ref err) => write!(f, "{}", err),
+            Error::RemoteError(ref err) => write!(f, "Remote error: {}", err),
+            Error::UnknownMethod(ref method) => write!(f, "Unknown method {}", method),
+            Error::Timeout => write!(f, "No response from remote node"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RpcMessage {
+    Request {
+        id: u32,
+        method: String,
+        params: Value,
+    },
+    Response {
+        id: u32,
+        #[serde(default)]
+        result: Option<Value>,
+        #[serde(default)]
+        error: Option<String>,
+        #[serde(default)]
+        unknown_method: bool,
+    },
+}
+
+impl RpcMessage {
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut message = vec![MAGIC];
+        message.extend(serde_json::to_vec(self)?);
+        Ok(message)
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        match data.split_first() {
+            Some((magic, body)) if *magic == MAGIC => serde_json::from_slice(body).ok(),
+            _ => None,
+        }
+    }
+}
+
+pub struct RpcClient {
+    transport: Transport,
+    next_id: u32,
+    pub timeout: Duration,
+    pub retries: u32,
+}
+
+impl RpcClient {
+    pub fn new(transport: Transport) -> Self {
+        let mut rng = rand::thread_rng();
+        Self {
+            transport,
+            next_id: rng.gen(),
+            timeout: Duration::from_secs(10),
+            retries: 2,
+        }
+    }
+
+    /// Call `method` on the node `addr` and decode its result. The request is
+    /// sent again up to `retries` times when it could not be delivered, or when
+    /// no response arrives in `timeout`.
+    pub fn call<P: Serialize, R: DeserializeOwned>(
+        &mut self,
+        device: &mut DigiMeshDevice,
+        addr: u64,
+        method: &str,
+        params: &P,
+    ) -> Result<R> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let request = RpcMessage::Request {
+            id,
+            method: method.to_string(),
+            params: serde_json::to_value(params)?,
+        }
+        .encode()?;
+
+        for _ in 0..=self.retries {
+            match self.transport.send_message(device, addr, &request[..]) {
+                Ok(_) => {}
+                // no response can come, send it again right away
+                Err(discover::Error::DeliveryError(_)) | Err(discover::Error::Timeout) => continue,
+                Err(err) => return Err(Error::DeviceError(err)),
+            }
+
+            let deadline = Instant::now() + self.timeout;
+            while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+                let (source, message) = match self.transport.recv_message(device, remaining)? {
+                    Some(message) => message,
+                    None => break,
+                };
+                if source != addr {
+                    continue;
+                }
+                match RpcMessage::decode(&message[..]) {
+                    Some(RpcMessage::Response {
+                        id: resp_id,
+                        result,
+                        error,
+                        unknown_method,
+                    }) if resp_id == id => {
+                        if unknown_method {
+                            return Err(Error::UnknownMethod(method.to_string()));
+                        }
+                        if let Some(error) = error {
+                            return Err(Error::RemoteError(error));
+                        }
+                        return Ok(serde_json::from_value(result.unwrap_or(Value::Null))?);
+                    }
+                    _ => continue,
+                }
+            }
+        }
+
+        Err(Error::Timeout)
+    }
+}
+
+type Handler = Box<dyn FnMut(u64, Value) -> std::result::Result<Value, String>>;
+
+pub struct RpcServer {
+    transport: Transport,
+    handlers: HashMap<String, Handler>,
+    replies: VecDeque<((u64, u32), Vec<u8>)>,
+}
+
+impl RpcServer {
+    pub fn new(transport: Transport) -> Self {
+        Self {
+            transport,
+            handlers: HashMap::new(),
+            replies: VecDeque::new(),
+        }
+    }
+
+    /// Register `handler` for `method`. It gets the caller address and the
+    /// request parameters, and returns the result or an error message.
+    pub fn register<F>(&mut self, method: &str, handler: F)
+    where
+        F: FnMut(u64, Value) -> std::result::Result<Value, String> + 'static,
+    {
+        self.handlers.insert(method.to_string(), Box::new(handler));
+    }
+
+    /// Answer incoming requests for `duration`
+    pub fn serve(&mut self, device: &mut DigiMeshDevice, duration: Duration) -> Result<()> {
+        let deadline = Instant::now() + duration;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            self.handle_next(device, remaining)?;
+        }
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for one request and answer it. Returns whether a
+    /// request was handled.
+    pub fn handle_next(&mut self, device: &mut DigiMeshDevice, timeout: Duration) -> Result<bool> {
+        let (source, message) = match self.transport.recv_message(device, timeout)? {
+            Some(message) => message,
+            None => return Ok(false),
+        };
+        let (id, method, params) = match RpcMessage::decode(&message[..]) {
+            Some(RpcMessage::Request { id, method, params }) => (id, method, params),
+            _ => return Ok(false),
+        };
+
+        // a retried request gets the response of the first run
+        let reply = match self.replies.iter().find(|(key, _)| *key == (source, id)) {
+            Some((_, reply)) => reply.clone(),
+            None => {
+                let response = match self.handlers.get_mut(&method) {
+                    Some(handler) => match handler(source, params) {
+                        Ok(result) => RpcMessage::Response {
+                            id,
+                            result: Some(result),
+                            error: None,
+                            unknown_method: false,
+                        },
+                        Err(error) => RpcMessage::Response {
+                            id,
+                            result: None,
+                            error: Some(error),
+                            unknown_method: false,
+                        },
+                    },
+                    None => RpcMessage::Response {
+                        id,
+                        result: None,
+                        error: None,
+                        unknown_method: true,
+                    },
+                };
+                let reply = response.encode()?;
+                if self.replies.len() >= REPLAY_LEN {
+                    self.replies.pop_front();
+                }
+                self.replies.push_back(((source, id), reply.clone()));
+                reply
+            }
+        };
+
+        match self.transport.send_message(device, source, &reply[..]) {
+            Ok(_) | Err(discover::Error::DeliveryError(_)) => Ok(true),
+            Err(err) => Err(Error::DeviceError(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn message_round_trip() {
+        let request = RpcMessage::Request {
+            id: 7,
+            method: "ping".to_string(),
+            params: json!({"count": 2}),
+        }
+        .encode()
+        .unwrap();
+        assert_eq!(request[0], MAGIC);
+
+        match RpcMessage::decode(&request[..]) {
+            Some(RpcMessage::Request { id, method, params }) => {
+                assert_eq!(id, 7);
+                assert_eq!(method, "ping");
+                assert_eq!(params, json!({"count": 2}));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn response_fields_default() {
+        let data = [&[MAGIC][..], br#"{"type":"response","id":3}"#].concat();
+        match RpcMessage::decode(&data[..]) {
+            Some(RpcMessage::Response {
+                id,
+                result,
+                error,
+                unknown_method,
+            }) => {
+                assert_eq!(id, 3);
+                assert_eq!(result, None);
+                assert_eq!(error, None);
+                assert!(!unknown_method);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn foreign_data_is_not_a_message() {
+        assert!(RpcMessage::decode(b"").is_none());
+        assert!(RpcMessage::decode(br#"{"type":"request"}"#).is_none());
+        assert!(RpcMessage::decode(&[MAGIC, b'{']).is_none());
+    }
+}
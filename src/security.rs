@@ -0,0 +1,419 @@
+#![allow(dead_code)]
+//!
+//! Network encryption: EE/KY management across the nodes of the network
+//!
+//! A node applying a new key or encryption setting can no longer talk to the
+//! nodes still using the old one. Changes are therefore queued on every node
+//! first, then applied with AC in one quick round, starting with the nodes
+//! the most hops away (coordinators last among equals) so that no node has to
+//! relay for a node which already switched, and the local module at the end.
+//! The local module applies its queued values with any local AT command, so
+//! it is staged last and only remote frames are sent until its own AC.
+//! A node which does not acknowledge AC is recorded as unconfirmed until it
+//! answers on the new setting. Each node is then checked and saved with WR.
+//!
+//! Key material never leaves `NetworkKey`: it has no `Display` nor
+//! `Serialize`, and its `Debug` is redacted.
+//!
+
+use crate::discover::{self, DeviceRole, DigiMeshDevice, NodeTarget};
+use crate::topology;
+use rand::Rng;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::time::Duration;
+
+pub const KEY_LEN: usize = 16;
+
+/// Timeout of the AC round, the response of a node which switched may not be readable
+static APPLY_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Timeouts of the neighbor survey and of the route traces ordering the AC round
+static NEIGHBOR_TIMEOUT: Duration = Duration::from_secs(10);
+static ROUTE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum Error {
+    DeviceError(discover::Error),
+    IOError(std::io::Error),
+    InvalidKey,
+    StagingFailed(Vec<NodeSecurity>), // a node could not take the change, nothing applied
+}
+
+impl From<discover::Error> for Error {
+    fn from(err: discover::Error) -> Self {
+        Error::DeviceError(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::IOError(err)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Error::DeviceError(ref err) => write!(f, "{}", err),
+            Error::IOError(ref err) => write!(f, "{}", err),
+            Error::InvalidKey => write!(f, "Invalid network key, 32 hex digits expected"),
+            Error::StagingFailed(ref nodes) => write!(
+                f,
+                "{} node(s) could not take the change, nothing was applied",
+                nodes.iter().filter(|n| !n.staged).count()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// 128-bit AES network key (KY)
+pub struct NetworkKey([u8; KEY_LEN]);
+
+impl NetworkKey {
+    pub fn generate() -> Self {
+        let mut key = [0u8; KEY_LEN];
+        rand::thread_rng().fill(&mut key[..]);
+        Self(key)
+    }
+
+    /// Parse 32 hex digits
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let hex = hex.trim();
+        if hex.len() != KEY_LEN * 2 || !hex.is_ascii() {
+            return Err(Error::InvalidKey);
+        }
+        let mut key = [0u8; KEY_LEN];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte =
+                u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| Error::InvalidKey)?;
+        }
+        Ok(Self(key))
+    }
+
+    /// Read a key file holding the key in hex
+    pub fn load(path: &str) -> Result<Self> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+        Self::from_hex(&contents)
+    }
+
+    /// Write the key in hex to a new file, never overwriting an existing key.
+    /// On unix the file is only readable by its owner.
+    pub fn save(&self, path: &str) -> Result<()> {
+        let hex: String = self.0.iter().map(|b| format!("{:02x}", b)).collect();
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path)?;
+        file.write_all(hex.as_bytes())?;
+        file.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.0[..]
+    }
+}
+
+impl std::fmt::Debug for NetworkKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("NetworkKey(********)")
+    }
+}
+
+/// Encryption state of one node and outcome of the last change
+#[derive(Debug, Clone)]
+pub struct NodeSecurity {
+    pub target: NodeTarget,
+    pub node_id: String,
+    pub role: DeviceRole,
+    pub encrypted: Option<bool>, // None when EE could not be read
+    pub staged: bool,
+    pub applied: bool,
+    pub unconfirmed: bool, // AC timed out, the node may or may not have switched
+    pub written: bool,
+    pub error: Option<String>,
+}
+
+impl NodeSecurity {
+    fn new(target: NodeTarget, node_id: String, role: DeviceRole) -> Self {
+        Self {
+            target,
+            node_id,
+            role,
+            encrypted: None,
+            staged: false,
+            applied: false,
+            unconfirmed: false,
+            written: false,
+            error: None,
+        }
+    }
+}
+
+/// Discovered nodes followed by the local module
+fn targets(device: &mut DigiMeshDevice) -> Result<Vec<NodeSecurity>> {
+    let mut nodes: Vec<NodeSecurity> = device
+        .nodes
+        .iter()
+        .flatten()
+        .map(|n| NodeSecurity::new(NodeTarget::Remote(n.addr_64bit), n.node_id.clone(), n.role))
+        .collect();
+    let local_id = device.get_node_id()?;
+    nodes.push(NodeSecurity::new(
+        NodeTarget::Local,
+        local_id,
+        DeviceRole::Unknown,
+    ));
+    Ok(nodes)
+}
+
+/// One exchange of a coordinated change
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Step {
+    Stage(NodeTarget),
+    Apply(NodeTarget),
+}
+
+/// Sort `nodes` farthest first, a node of unknown distance being taken as the
+/// farthest and the coordinators coming last among nodes at the same distance,
+/// with the local module at the end. Every node is staged in that order, then
+/// applied in the same order.
+fn change_plan(nodes: &mut [NodeSecurity], hops: &HashMap<u64, usize>) -> Vec<Step> {
+    nodes.sort_by_key(|node| match node.target {
+        NodeTarget::Local => (true, Reverse(0), false),
+        NodeTarget::Remote(addr) => (
+            false,
+            Reverse(hops.get(&addr).copied().unwrap_or(usize::MAX)),
+            node.role == DeviceRole::Coordinator,
+        ),
+    });
+    let stages = nodes.iter().map(|n| Step::Stage(n.target));
+    let applies = nodes.iter().map(|n| Step::Apply(n.target));
+    stages.chain(applies).collect()
+}
+
+/// Hop count from the local module to every discovered node, from the
+/// neighbor tables when they can be surveyed, from a route trace otherwise
+fn hop_counts(device: &mut DigiMeshDevice) -> Result<HashMap<u64, usize>> {
+    let local_addr = device.get_64bit_addr()?;
+    let addrs: Vec<u64> = device
+        .nodes
+        .iter()
+        .flatten()
+        .map(|n| n.addr_64bit)
+        .collect();
+    let mesh = topology::survey(device, NEIGHBOR_TIMEOUT).ok();
+
+    let mut hops = HashMap::new();
+    for addr in addrs {
+        let count = match mesh.as_ref().and_then(|m| m.hop_count(local_addr, addr)) {
+            Some(count) => Some(count),
+            None => match device.trace_route(addr, ROUTE_TIMEOUT) {
+                Ok(route) if !route.is_empty() => Some(route.len()),
+                _ => None,
+            },
+        };
+        if let Some(count) = count {
+            hops.insert(addr, count);
+        }
+    }
+    Ok(hops)
+}
+
+fn read_encryption(device: &mut DigiMeshDevice, target: NodeTarget) -> discover::Result<bool> {
+    let ee = device.get_param(target, "EE")?;
+    Ok(ee.iter().any(|b| *b != 0))
+}
+
+/// Read EE on every discovered node and on the local module. A node which
+/// does not answer may use another key than the local module.
+pub fn verify(device: &mut DigiMeshDevice) -> Result<Vec<NodeSecurity>> {
+    let mut nodes = targets(device)?;
+    for node in nodes.iter_mut() {
+        match read_encryption(device, node.target) {
+            Ok(encrypted) => node.encrypted = Some(encrypted),
+            Err(err) => node.error = Some(err.to_string()),
+        }
+    }
+    Ok(nodes)
+}
+
+/// Queue KY (when given) then EE on every node, apply them everywhere with
+/// AC, farthest nodes first and local module last, then read EE back and save
+/// with WR. The hop counts are read before anything is queued. When a node
+/// cannot take the change, nothing is applied and `StagingFailed` is returned;
+/// the values queued on the other nodes stay pending until their next AC, so
+/// run the operation again once the node is back.
+fn coordinated_change(
+    device: &mut DigiMeshDevice,
+    key: Option<&NetworkKey>,
+    encrypt: bool,
+) -> Result<Vec<NodeSecurity>> {
+    let mut nodes = targets(device)?;
+    let ee = [encrypt as u8];
+    let hops = hop_counts(device)?;
+
+    for step in change_plan(&mut nodes, &hops) {
+        match step {
+            Step::Stage(target) => {
+                // a local module with queued values would apply them with its
+                // next AT command, so it is only staged when the others are
+                if target == NodeTarget::Local
+                    && nodes.iter().any(|n| !n.staged && n.target != target)
+                {
+                    return Err(Error::StagingFailed(nodes));
+                }
+                let node = nodes.iter_mut().find(|n| n.target == target).unwrap();
+                let staged = match key {
+                    Some(key) => device.queue_param(target, "KY", key.as_bytes()),
+                    None => Ok(()),
+                }
+                .and_then(|_| device.queue_param(target, "EE", &ee));
+                match staged {
+                    Ok(_) => node.staged = true,
+                    Err(err) => node.error = Some(err.to_string()),
+                }
+                if target == NodeTarget::Local && !node.staged {
+                    return Err(Error::StagingFailed(nodes));
+                }
+            }
+            // one quick round without retries: a node which switched cannot be
+            // reached any more until the local module switches too
+            Step::Apply(target) => {
+                let applied = match target {
+                    NodeTarget::Local => device.execute(target, "AC", None).map(|_| ()),
+                    NodeTarget::Remote(addr) => device
+                        .remote_at_command(addr, "AC", None, false, APPLY_TIMEOUT)
+                        .map(|_| ()),
+                };
+                let node = nodes.iter_mut().find(|n| n.target == target).unwrap();
+                match applied {
+                    Ok(_) => node.applied = true,
+                    Err(discover::Error::Timeout) => node.unconfirmed = true,
+                    Err(err) => node.error = Some(err.to_string()),
+                }
+            }
+        }
+    }
+
+    // a node answering now shares the setting of the local module, which
+    // confirms a node whose AC timed out
+    for node in nodes.iter_mut() {
+        let checked = read_encryption(device, node.target).and_then(|encrypted| {
+            node.encrypted = Some(encrypted);
+            match node.target {
+                NodeTarget::Local => device.execute(node.target, "WR", None).map(|_| ()),
+                NodeTarget::Remote(addr) => device.remote_write(addr),
+            }
+        });
+        if node.encrypted.is_some() && node.unconfirmed {
+            node.unconfirmed = false;
+            node.applied = true;
+        }
+        match checked {
+            Ok(_) => node.written = true,
+            Err(err) => node.error = Some(err.to_string()),
+        }
+    }
+    Ok(nodes)
+}
+
+/// Enable encryption on the whole network with `key`. The key is always set:
+/// the nodes may have no key yet, or different ones.
+pub fn enable_encryption(
+    device: &mut DigiMeshDevice,
+    key: &NetworkKey,
+) -> Result<Vec<NodeSecurity>> {
+    coordinated_change(device, Some(key), true)
+}
+
+/// Disable encryption on the whole network
+pub fn disable_encryption(device: &mut DigiMeshDevice) -> Result<Vec<NodeSecurity>> {
+    coordinated_change(device, None, false)
+}
+
+/// Replace the network key on every discovered node and on the local module,
+/// keeping encryption enabled
+pub fn rotate_key(device: &mut DigiMeshDevice, key: &NetworkKey) -> Result<Vec<NodeSecurity>> {
+    coordinated_change(device, Some(key), true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(target: NodeTarget, role: DeviceRole) -> NodeSecurity {
+        NodeSecurity::new(target, String::new(), role)
+    }
+
+    #[test]
+    fn from_hex_parses_32_digits() {
+        let key = NetworkKey::from_hex(" 000102030405060708090a0B0c0D0e0F\n").unwrap();
+        let expected: Vec<u8> = (0..KEY_LEN as u8).collect();
+        assert_eq!(key.as_bytes(), &expected[..]);
+    }
+
+    #[test]
+    fn from_hex_rejects_bad_keys() {
+        for hex in [
+            "",
+            "0001",
+            "000102030405060708090a0b0c0d0e0f00",
+            "000102030405060708090a0b0c0d0e0g",
+            "é0102030405060708090a0b0c0d0e0f",
+        ] {
+            assert!(
+                matches!(NetworkKey::from_hex(hex), Err(Error::InvalidKey)),
+                "{}",
+                hex
+            );
+        }
+    }
+
+    #[test]
+    fn generate_gives_distinct_redacted_keys() {
+        let first = NetworkKey::generate();
+        let second = NetworkKey::generate();
+        assert_eq!(first.as_bytes().len(), KEY_LEN);
+        assert_ne!(first.as_bytes(), second.as_bytes());
+        assert_eq!(format!("{:?}", first), "NetworkKey(********)");
+    }
+
+    #[test]
+    fn change_plan_stages_everything_then_applies_farthest_first() {
+        let mut nodes = vec![
+            node(NodeTarget::Local, DeviceRole::Unknown),
+            node(NodeTarget::Remote(1), DeviceRole::Router),
+            node(NodeTarget::Remote(2), DeviceRole::Coordinator),
+            node(NodeTarget::Remote(3), DeviceRole::Router),
+            node(NodeTarget::Remote(4), DeviceRole::EndDevice),
+        ];
+        let hops: HashMap<u64, usize> = [(1, 1), (2, 2), (3, 2)].into_iter().collect();
+
+        let order = [
+            NodeTarget::Remote(4), // unknown distance
+            NodeTarget::Remote(3),
+            NodeTarget::Remote(2), // coordinator after the router at the same distance
+            NodeTarget::Remote(1),
+            NodeTarget::Local,
+        ];
+        let expected: Vec<Step> = order
+            .iter()
+            .map(|t| Step::Stage(*t))
+            .chain(order.iter().map(|t| Step::Apply(*t)))
+            .collect();
+        assert_eq!(change_plan(&mut nodes, &hops), expected);
+    }
+}
@@ -0,0 +1,488 @@
+#![allow(dead_code)]
+//!
+//! Sleep management: sleep parameters and messaging towards sleeping nodes
+//!
+//! With synchronous cyclic sleep the whole network wakes up and goes to sleep
+//! together, which the local module reports with Network Woke (0x0B) and
+//! Network Sleep (0x0C) modem status. Messages for sleeping nodes are held
+//! until the next wake window instead of failing.
+//!
+
+use crate::api::{self, ModemStatusKind};
+use crate::discover::{self, DigiMeshDevice, NodeTarget, RemoteDigiMeshDevice};
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// SM values
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SleepMode {
+    NoSleep,
+    PinSleep,
+    AsyncCyclic,
+    AsyncCyclicPinWake,
+    SleepSupport,
+    SyncCyclic,
+    Unknown(u8),
+}
+
+impl SleepMode {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0 => SleepMode::NoSleep,
+            1 => SleepMode::PinSleep,
+            4 => SleepMode::AsyncCyclic,
+            5 => SleepMode::AsyncCyclicPinWake,
+            7 => SleepMode::SleepSupport,
+            8 => SleepMode::SyncCyclic,
+            other => SleepMode::Unknown(other),
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        match *self {
+            SleepMode::NoSleep => 0,
+            SleepMode::PinSleep => 1,
+            SleepMode::AsyncCyclic => 4,
+            SleepMode::AsyncCyclicPinWake => 5,
+            SleepMode::SleepSupport => 7,
+            SleepMode::SyncCyclic => 8,
+            SleepMode::Unknown(code) => code,
+        }
+    }
+
+    /// whether the node spends time unreachable
+    pub fn sleeps(&self) -> bool {
+        !matches!(self, SleepMode::NoSleep | SleepMode::SleepSupport)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SleepConfig {
+    pub mode: SleepMode,
+    pub sleep_period: Duration, // SP, in steps of 10 ms
+    pub wake_time: Duration,    // ST, in ms
+    pub options: u16,           // SO
+}
+
+impl SleepConfig {
+    /// time between the start of two wake windows
+    pub fn cycle(&self) -> Duration {
+        self.sleep_period + self.wake_time
+    }
+}
+
+fn read_value(
+    device: &mut DigiMeshDevice,
+    target: NodeTarget,
+    atcmd: &str,
+) -> discover::Result<u64> {
+    let data = device.get_param(target, atcmd)?;
+    Ok(data.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64))
+}
+
+/// big endian value without its leading zeros
+fn encode_value(val: u64) -> Vec<u8> {
+    let bytes = val.to_be_bytes();
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(7);
+    bytes[start..].to_vec()
+}
+
+/// Read SM, SP, ST and SO of the local module or of a remote node
+pub fn read_sleep_config(
+    device: &mut DigiMeshDevice,
+    target: NodeTarget,
+) -> discover::Result<SleepConfig> {
+    Ok(SleepConfig {
+        mode: SleepMode::from_code(read_value(device, target, "SM")? as u8),
+        sleep_period: Duration::from_millis(read_value(device, target, "SP")? * 10),
+        wake_time: Duration::from_millis(read_value(device, target, "ST")?),
+        options: read_value(device, target, "SO")? as u16,
+    })
+}
+
+/// Queue SM, SP, ST and SO on the local module or on a remote node and apply
+/// them together with AC. A remote node must be awake to take them.
+pub fn write_sleep_config(
+    device: &mut DigiMeshDevice,
+    target: NodeTarget,
+    config: &SleepConfig,
+) -> discover::Result<()> {
+    let sp = (config.sleep_period.as_millis() / 10) as u64;
+    let st = config.wake_time.as_millis() as u64;
+    device.queue_param(target, "SP", &encode_value(sp)[..])?;
+    device.queue_param(target, "ST", &encode_value(st)[..])?;
+    device.queue_param(target, "SO", &encode_value(config.options as u64)[..])?;
+    // the mode last, so the node starts sleeping with the new timings
+    device.queue_param(target, "SM", &[config.mode.code()])?;
+    match target {
+        NodeTarget::Local => device.execute(target, "AC", None).map(|_| ()),
+        NodeTarget::Remote(addr) => device.remote_apply(addr),
+    }
+}
+
+/// Sleep cycle of a remote node, None when it does not sleep
+pub fn learn_sleep_cycle(
+    device: &mut DigiMeshDevice,
+    addr: u64,
+) -> discover::Result<Option<Duration>> {
+    let config = read_sleep_config(device, NodeTarget::Remote(addr))?;
+    Ok(if config.mode.sleeps() {
+        Some(config.cycle())
+    } else {
+        None
+    })
+}
+
+/// Silence tolerated between two detections of a node that stays awake,
+/// covering the length of a discovery cycle and the pause between two cycles
+pub static DISCOVERY_TOLERANCE: Duration = Duration::from_secs(10);
+
+/// A period during which a node was not considered present
+#[derive(Debug, Clone)]
+pub struct Absence {
+    pub start: Instant,
+    pub end: Instant,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Presence {
+    pub spans: Vec<(Instant, Instant)>,
+    pub absences: Vec<Absence>,
+}
+
+/// Merge the detections of a node into presence spans. A node missing from
+/// discovery stays present for its sleep cycle plus `margin` (or
+/// `DISCOVERY_TOLERANCE` plus `margin` when it does not sleep), and is marked
+/// absent after that, with the reason. `scan_end` closes the last span.
+pub fn presence(
+    detections: &[(Instant, Instant)],
+    sleep_cycle: Option<Duration>,
+    margin: Duration,
+    scan_end: Instant,
+) -> Presence {
+    let tolerance = sleep_cycle.unwrap_or(DISCOVERY_TOLERANCE) + margin;
+    let reason = |silence: Duration| match sleep_cycle {
+        Some(cycle) => format!(
+            "missed discovery for {}s, longer than its sleep cycle of {}s plus {}s",
+            silence.as_secs(),
+            cycle.as_secs(),
+            margin.as_secs()
+        ),
+        None => format!(
+            "missed discovery for {}s and does not sleep",
+            silence.as_secs()
+        ),
+    };
+
+    let mut result = Presence::default();
+    let mut detections = detections.iter();
+    let mut current = match detections.next() {
+        Some(detection) => *detection,
+        None => return result,
+    };
+
+    for &(start, end) in detections {
+        let silence = start.saturating_duration_since(current.1);
+        if silence > tolerance {
+            // present until the node should have woken up again
+            let absent_from = current.1 + tolerance;
+            result.spans.push((current.0, absent_from));
+            result.absences.push(Absence {
+                start: absent_from,
+                end: start,
+                reason: reason(silence),
+            });
+            current = (start, end);
+        } else {
+            current.1 = current.1.max(end);
+        }
+    }
+
+    let silence = scan_end.saturating_duration_since(current.1);
+    if silence > tolerance {
+        let absent_from = current.1 + tolerance;
+        result.spans.push((current.0, absent_from));
+        result.absences.push(Absence {
+            start: absent_from,
+            end: scan_end,
+            reason: reason(silence),
+        });
+    } else {
+        result.spans.push((current.0, scan_end));
+    }
+    result
+}
+
+/// A message waiting for the wake window of its destination
+#[derive(Debug, Clone)]
+pub struct QueuedMessage {
+    pub addr: u64,
+    pub payload: Vec<u8>,
+    pub queued: Instant,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SendOutcome {
+    Sent(u8), // retries
+    Queued,
+}
+
+/// Tracks the network sleep state and holds messages for sleeping nodes
+pub struct SleepManager {
+    events: mpsc::Receiver<api::ModemStatus>,
+    network_awake: Option<bool>,
+    sleepy: HashMap<u64, SleepConfig>,
+    cycles: HashMap<u64, Duration>, // sleep cycles known from discovery
+    queue: VecDeque<QueuedMessage>,
+    /// messages older than this are dropped and reported as expired
+    pub max_age: Duration,
+}
+
+impl SleepManager {
+    /// Track the discovered nodes known to sleep, see `update_nodes`
+    pub fn new(device: &mut DigiMeshDevice) -> Self {
+        let mut manager = Self {
+            events: device.subscribe_modem_status(),
+            network_awake: None,
+            sleepy: HashMap::new(),
+            cycles: HashMap::new(),
+            queue: VecDeque::new(),
+            max_age: Duration::from_secs(3600),
+        };
+        manager.update_nodes(device.nodes.iter().flatten());
+        manager
+    }
+
+    /// Take the sleep cycles of discovered nodes, learned by scheduled scans
+    /// or configured, into account
+    pub fn update_nodes<'a>(&mut self, nodes: impl IntoIterator<Item = &'a RemoteDigiMeshDevice>) {
+        for node in nodes {
+            match node.sleep_cycle {
+                Some(cycle) => self.cycles.insert(node.addr_64bit, cycle),
+                None => self.cycles.remove(&node.addr_64bit),
+            };
+        }
+    }
+
+    /// whether messages to `addr` may have to wait for its wake window
+    pub fn sleeps(&self, addr: u64) -> bool {
+        self.sleepy.contains_key(&addr) || self.cycles.contains_key(&addr)
+    }
+
+    /// Declare the sleep configuration of a node
+    pub fn register_node(&mut self, addr: u64, config: SleepConfig) {
+        if config.mode.sleeps() {
+            self.sleepy.insert(addr, config);
+        } else {
+            self.sleepy.remove(&addr);
+        }
+    }
+
+    /// Read the sleep configuration of a node and register it
+    pub fn learn_node(
+        &mut self,
+        device: &mut DigiMeshDevice,
+        addr: u64,
+    ) -> discover::Result<SleepConfig> {
+        let config = read_sleep_config(device, NodeTarget::Remote(addr))?;
+        self.register_node(addr, config);
+        Ok(config)
+    }
+
+    pub fn sleep_config(&self, addr: u64) -> Option<&SleepConfig> {
+        self.sleepy.get(&addr)
+    }
+
+    /// Last network state reported by the local module, None before the first event
+    pub fn network_awake(&self) -> Option<bool> {
+        self.network_awake
+    }
+
+    pub fn queued(&self) -> impl Iterator<Item = &QueuedMessage> {
+        self.queue.iter()
+    }
+
+    fn update_state(&mut self) -> bool {
+        let mut woke = false;
+        while let Ok(status) = self.events.try_recv() {
+            match status.status {
+                ModemStatusKind::NetworkWoke => {
+                    self.network_awake = Some(true);
+                    woke = true;
+                }
+                ModemStatusKind::NetworkSleep => self.network_awake = Some(false),
+                _ => {}
+            }
+        }
+        woke
+    }
+
+    /// Send `payload` to `addr` now, or queue it when the node is asleep or
+    /// cannot be reached because it sleeps
+    pub fn send_data(
+        &mut self,
+        device: &mut DigiMeshDevice,
+        addr: u64,
+        payload: &[u8],
+    ) -> discover::Result<SendOutcome> {
+        self.update_state();
+        let sleepy = self.sleeps(addr);
+        if sleepy && self.network_awake == Some(false) {
+            self.enqueue(addr, payload);
+            return Ok(SendOutcome::Queued);
+        }
+
+        match device.send_data(addr, payload, None) {
+            Ok(retries) => Ok(SendOutcome::Sent(retries)),
+            Err(discover::Error::DeliveryError(_)) | Err(discover::Error::Timeout) if sleepy => {
+                self.enqueue(addr, payload);
+                Ok(SendOutcome::Queued)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn enqueue(&mut self, addr: u64, payload: &[u8]) {
+        self.queue.push_back(QueuedMessage {
+            addr,
+            payload: payload.to_vec(),
+            queued: Instant::now(),
+        });
+    }
+
+    /// Try to deliver every queued message, returning the delivered ones and
+    /// the expired ones
+    pub fn flush(
+        &mut self,
+        device: &mut DigiMeshDevice,
+    ) -> discover::Result<(Vec<QueuedMessage>, Vec<QueuedMessage>)> {
+        let mut delivered = Vec::new();
+        let mut expired = Vec::new();
+        let mut remaining = VecDeque::new();
+
+        while let Some(message) = self.queue.pop_front() {
+            if message.queued.elapsed() > self.max_age {
+                expired.push(message);
+                continue;
+            }
+            match device.send_data(message.addr, &message.payload[..], None) {
+                Ok(_) => delivered.push(message),
+                Err(discover::Error::DeliveryError(_)) | Err(discover::Error::Timeout) => {
+                    remaining.push_back(message)
+                }
+                Err(err) => {
+                    remaining.push_back(message);
+                    remaining.extend(self.queue.drain(..));
+                    self.queue = remaining;
+                    return Err(err);
+                }
+            }
+        }
+        self.queue = remaining;
+        Ok((delivered, expired))
+    }
+
+    /// Read incoming frames for `duration`, sending the queued messages as
+    /// soon as the network wakes up
+    pub fn poll(
+        &mut self,
+        device: &mut DigiMeshDevice,
+        duration: Duration,
+    ) -> discover::Result<(Vec<QueuedMessage>, Vec<QueuedMessage>)> {
+        let mut delivered = Vec::new();
+        let mut expired = Vec::new();
+        let deadline = Instant::now() + duration;
+
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            device.poll(remaining.min(Duration::from_millis(500)))?;
+            if self.update_state() && !self.queue.is_empty() {
+                let (sent, dropped) = self.flush(device)?;
+                delivered.extend(sent);
+                expired.extend(dropped);
+            }
+        }
+        Ok((delivered, expired))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(start: Instant, secs: u64) -> Instant {
+        start + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn presence_merges_detections_within_tolerance() {
+        let t0 = Instant::now();
+        let detections = [
+            (at(t0, 0), at(t0, 1)),
+            (at(t0, 6), at(t0, 7)),
+            (at(t0, 12), at(t0, 13)),
+        ];
+        let result = presence(&detections, None, Duration::from_secs(0), at(t0, 15));
+
+        assert_eq!(result.spans, vec![(at(t0, 0), at(t0, 15))]);
+        assert!(result.absences.is_empty());
+    }
+
+    #[test]
+    fn presence_tolerates_silence_up_to_sleep_cycle_plus_margin() {
+        let t0 = Instant::now();
+        let cycle = Some(Duration::from_secs(60));
+        let margin = Duration::from_secs(10);
+
+        // silence of exactly the cycle plus the margin
+        let detections = [(at(t0, 0), at(t0, 1)), (at(t0, 71), at(t0, 72))];
+        let result = presence(&detections, cycle, margin, at(t0, 72));
+        assert_eq!(result.spans, vec![(at(t0, 0), at(t0, 72))]);
+        assert!(result.absences.is_empty());
+
+        // one second longer
+        let detections = [(at(t0, 0), at(t0, 1)), (at(t0, 72), at(t0, 73))];
+        let result = presence(&detections, cycle, margin, at(t0, 73));
+        assert_eq!(
+            result.spans,
+            vec![(at(t0, 0), at(t0, 71)), (at(t0, 72), at(t0, 73))]
+        );
+        assert_eq!(result.absences.len(), 1);
+        assert_eq!(result.absences[0].start, at(t0, 71));
+        assert_eq!(result.absences[0].end, at(t0, 72));
+    }
+
+    #[test]
+    fn presence_records_absence_reason() {
+        let t0 = Instant::now();
+        let margin = Duration::from_secs(5);
+        let detections = [(at(t0, 0), at(t0, 1))];
+
+        let sleeping = presence(
+            &detections,
+            Some(Duration::from_secs(30)),
+            margin,
+            at(t0, 101),
+        );
+        assert_eq!(sleeping.spans, vec![(at(t0, 0), at(t0, 36))]);
+        assert_eq!(
+            sleeping.absences[0].reason,
+            "missed discovery for 100s, longer than its sleep cycle of 30s plus 5s"
+        );
+
+        let awake = presence(&detections, None, margin, at(t0, 101));
+        assert_eq!(awake.spans, vec![(at(t0, 0), at(t0, 16))]);
+        assert_eq!(
+            awake.absences[0].reason,
+            "missed discovery for 100s and does not sleep"
+        );
+    }
+
+    #[test]
+    fn presence_without_detection_is_empty() {
+        let result = presence(&[], None, Duration::from_secs(5), Instant::now());
+        assert!(result.spans.is_empty());
+        assert!(result.absences.is_empty());
+    }
+}
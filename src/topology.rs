@@ -0,0 +1,288 @@
+#![allow(dead_code)]
+//!
+//! Mesh topology built from the neighbor tables (FN) of every node
+//!
+//!
+
+use crate::discover::{self, DeviceRole, DigiMeshDevice};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::time::Duration;
+
+/// One entry of a neighbor table as reported by FN
+#[derive(Debug, Clone)]
+pub struct Neighbor {
+    pub addr_64bit: u64,
+    pub node_id: String,
+    pub role: DeviceRole,
+    pub rssi: Option<u8>, // signal strength of the link in -dBm
+}
+
+/// An undirected link between two nodes, `a` being the lower address
+#[derive(Debug, Clone, PartialEq)]
+pub struct Link {
+    pub a: u64,
+    pub b: u64,
+    pub rssi: Option<u8>,
+}
+
+#[derive(Debug, Default)]
+pub struct Topology {
+    nodes: BTreeMap<u64, String>,
+    roles: BTreeMap<u64, DeviceRole>,
+    adjacency: BTreeMap<u64, BTreeMap<u64, Option<u8>>>,
+}
+
+impl Topology {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_node(&mut self, addr: u64, node_id: &str) {
+        let entry = self.nodes.entry(addr).or_default();
+        if entry.is_empty() {
+            *entry = node_id.to_string();
+        }
+        self.adjacency.entry(addr).or_default();
+    }
+
+    /// Record the neighbor table reported by `addr`. When both ends of a link
+    /// report it, the weaker RSSI is kept since it limits the link.
+    pub fn add_neighbors(&mut self, addr: u64, neighbors: &[Neighbor]) {
+        self.add_node(addr, "");
+        for neighbor in neighbors {
+            if neighbor.addr_64bit == addr {
+                continue;
+            }
+            self.add_node(neighbor.addr_64bit, &neighbor.node_id);
+            if neighbor.role != DeviceRole::Unknown {
+                self.roles.insert(neighbor.addr_64bit, neighbor.role);
+            }
+            self.add_link(addr, neighbor.addr_64bit, neighbor.rssi);
+        }
+    }
+
+    fn add_link(&mut self, a: u64, b: u64, rssi: Option<u8>) {
+        let rssi = match (self.adjacency[&a].get(&b), rssi) {
+            (Some(Some(known)), Some(new)) => Some((*known).max(new)),
+            (Some(known), None) => *known,
+            (_, new) => new,
+        };
+        self.adjacency.get_mut(&a).unwrap().insert(b, rssi);
+        self.adjacency.get_mut(&b).unwrap().insert(a, rssi);
+    }
+
+    pub fn node_id(&self, addr: u64) -> Option<&str> {
+        self.nodes.get(&addr).map(|s| s.as_str())
+    }
+
+    pub fn role(&self, addr: u64) -> DeviceRole {
+        self.roles.get(&addr).copied().unwrap_or(DeviceRole::Unknown)
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = (u64, &str)> {
+        self.nodes.iter().map(|(addr, id)| (*addr, id.as_str()))
+    }
+
+    pub fn links(&self) -> Vec<Link> {
+        let mut links = Vec::new();
+        for (a, neighbors) in &self.adjacency {
+            for (b, rssi) in neighbors {
+                if a < b {
+                    links.push(Link {
+                        a: *a,
+                        b: *b,
+                        rssi: *rssi,
+                    });
+                }
+            }
+        }
+        links
+    }
+
+    pub fn neighbors(&self, addr: u64) -> Vec<(u64, Option<u8>)> {
+        match self.adjacency.get(&addr) {
+            Some(neighbors) => neighbors.iter().map(|(a, r)| (*a, *r)).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Shortest path in hops from `from` to `to`, both ends included
+    pub fn shortest_path(&self, from: u64, to: u64) -> Option<Vec<u64>> {
+        if !self.adjacency.contains_key(&from) || !self.adjacency.contains_key(&to) {
+            return None;
+        }
+
+        let mut previous: BTreeMap<u64, u64> = BTreeMap::new();
+        let mut visited: BTreeSet<u64> = BTreeSet::new();
+        let mut queue = VecDeque::new();
+        visited.insert(from);
+        queue.push_back(from);
+
+        while let Some(node) = queue.pop_front() {
+            if node == to {
+                let mut path = vec![to];
+                let mut current = to;
+                while let Some(prev) = previous.get(&current) {
+                    path.push(*prev);
+                    current = *prev;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for next in self.adjacency[&node].keys() {
+                if visited.insert(*next) {
+                    previous.insert(*next, node);
+                    queue.push_back(*next);
+                }
+            }
+        }
+        None
+    }
+
+    pub fn hop_count(&self, from: u64, to: u64) -> Option<usize> {
+        self.shortest_path(from, to).map(|path| path.len() - 1)
+    }
+
+    /// Nodes whose loss would split the mesh in two or more parts
+    pub fn articulation_points(&self) -> Vec<u64> {
+        let mut state = ArticulationState::default();
+        for node in self.adjacency.keys() {
+            if !state.discovery.contains_key(node) {
+                self.visit(*node, None, &mut state);
+            }
+        }
+        state.points.into_iter().collect()
+    }
+
+    // Tarjan's algorithm; the mesh is small enough for recursion
+    fn visit(&self, node: u64, parent: Option<u64>, state: &mut ArticulationState) {
+        state.time += 1;
+        state.discovery.insert(node, state.time);
+        state.low.insert(node, state.time);
+        let mut children = 0;
+
+        for next in self.adjacency[&node].keys() {
+            if !state.discovery.contains_key(next) {
+                children += 1;
+                self.visit(*next, Some(node), state);
+                let low = state.low[&node].min(state.low[next]);
+                state.low.insert(node, low);
+                if parent.is_some() && state.low[next] >= state.discovery[&node] {
+                    state.points.insert(node);
+                }
+            } else if Some(*next) != parent {
+                let low = state.low[&node].min(state.discovery[next]);
+                state.low.insert(node, low);
+            }
+        }
+
+        if parent.is_none() && children > 1 {
+            state.points.insert(node);
+        }
+    }
+}
+
+#[derive(Default)]
+struct ArticulationState {
+    time: usize,
+    discovery: BTreeMap<u64, usize>,
+    low: BTreeMap<u64, usize>,
+    points: BTreeSet<u64>,
+}
+
+/// Query the neighbor table of the local module and of every discovered node
+/// and assemble them into a topology graph.
+pub fn survey(device: &mut DigiMeshDevice, timeout: Duration) -> discover::Result<Topology> {
+    let mut topology = Topology::new();
+
+    let local_addr = device.get_64bit_addr()?;
+    let local_id = device.get_node_id()?;
+    topology.add_node(local_addr, &local_id);
+    let neighbors = device.find_neighbors(None, timeout)?;
+    topology.add_neighbors(local_addr, &neighbors);
+
+    let remotes: Vec<(u64, String, DeviceRole)> = match &device.nodes {
+        Some(nodes) => nodes
+            .iter()
+            .map(|n| (n.addr_64bit, n.node_id.clone(), n.role))
+            .collect(),
+        None => Vec::new(),
+    };
+
+    for (addr, node_id, role) in remotes {
+        topology.add_node(addr, &node_id);
+        if role != DeviceRole::Unknown {
+            topology.roles.insert(addr, role);
+        }
+        let neighbors = device.find_neighbors(Some(addr), timeout)?;
+        topology.add_neighbors(addr, &neighbors);
+    }
+
+    Ok(topology)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn neighbor(addr: u64, rssi: Option<u8>) -> Neighbor {
+        Neighbor {
+            addr_64bit: addr,
+            node_id: format!("N{}", addr),
+            role: DeviceRole::Router,
+            rssi,
+        }
+    }
+
+    /// 1 - 2 - 3 - 4 with 5 hanging off 2, and 6 - 7 apart
+    fn mesh() -> Topology {
+        let mut topology = Topology::new();
+        topology.add_neighbors(1, &[neighbor(2, Some(40))]);
+        topology.add_neighbors(2, &[neighbor(3, None), neighbor(5, None)]);
+        topology.add_neighbors(3, &[neighbor(4, None)]);
+        topology.add_neighbors(6, &[neighbor(7, None)]);
+        topology
+    }
+
+    #[test]
+    fn shortest_path_and_hop_count() {
+        let topology = mesh();
+        assert_eq!(topology.shortest_path(1, 4), Some(vec![1, 2, 3, 4]));
+        assert_eq!(topology.shortest_path(5, 5), Some(vec![5]));
+        assert_eq!(topology.hop_count(5, 4), Some(3));
+        assert_eq!(topology.hop_count(1, 7), None);
+        assert_eq!(topology.hop_count(1, 99), None);
+    }
+
+    #[test]
+    fn articulation_points_split_the_mesh() {
+        let mut points = mesh().articulation_points();
+        points.sort();
+        assert_eq!(points, vec![2, 3]);
+    }
+
+    #[test]
+    fn cycle_has_no_articulation_point() {
+        let mut topology = Topology::new();
+        topology.add_neighbors(1, &[neighbor(2, None), neighbor(3, None)]);
+        topology.add_neighbors(2, &[neighbor(3, None)]);
+        assert!(topology.articulation_points().is_empty());
+        assert_eq!(topology.hop_count(1, 3), Some(1));
+    }
+
+    #[test]
+    fn link_keeps_the_weaker_rssi() {
+        let mut topology = Topology::new();
+        topology.add_neighbors(1, &[neighbor(2, Some(40))]);
+        topology.add_neighbors(2, &[neighbor(1, Some(55)), neighbor(2, Some(10))]);
+        assert_eq!(
+            topology.links(),
+            vec![Link {
+                a: 1,
+                b: 2,
+                rssi: Some(55),
+            }]
+        );
+        assert_eq!(topology.node_id(2), Some("N2"));
+    }
+}
@@ -0,0 +1,226 @@
+#![allow(dead_code)]
+//!
+//! Command mode sessions for modules in transparent mode (AP=0)
+//!
+//! A session enters command mode when it is created and sends CN when it is
+//! dropped, so a module is never left waiting for the command mode timeout.
+//! Factory modules are in transparent mode; `enable_api_mode` switches them to
+//! the API mode the rest of the crate relies on.
+//!
+
+use crate::api::{AtCommands, AtStatus};
+use crate::discover::{self, DeviceRole, DigiMeshDevice, NodeTarget, RemoteDigiMeshDevice};
+use std::time::Duration;
+
+pub struct CommandSession<'a> {
+    device: &'a mut DigiMeshDevice,
+    active: bool,
+}
+
+impl<'a> CommandSession<'a> {
+    pub fn enter(device: &'a mut DigiMeshDevice) -> discover::Result<Self> {
+        device.command_mode(true)?;
+        Ok(Self {
+            device,
+            active: true,
+        })
+    }
+
+    /// Run `atcmd` with an optional parameter written as the module expects it
+    /// in command mode (hex for numbers), and return the response line
+    pub fn command(&mut self, atcmd: &str, param: Option<&str>) -> discover::Result<String> {
+        let lines = self
+            .device
+            .atcmd(&AtCommands::AtCmd((atcmd, param.map(|p| p.as_bytes()))).create())?;
+        match lines.into_iter().next() {
+            Some(line) if line == "ERROR" => Err(discover::Error::CommandError(
+                atcmd.to_string(),
+                AtStatus::Error,
+            )),
+            Some(line) => Ok(line),
+            None => Err(discover::Error::Timeout),
+        }
+    }
+
+    /// Read a numeric parameter
+    pub fn get_value(&mut self, atcmd: &str) -> discover::Result<u64> {
+        let line = self.command(atcmd, None)?;
+        u64::from_str_radix(line.trim(), 16).map_err(|_| {
+            discover::Error::InvalidMode(format!("Invalid response to {}: {}", atcmd, line))
+        })
+    }
+
+    /// Discover the nodes of the network with ND, waiting for NT
+    pub fn discover(&mut self) -> discover::Result<Vec<RemoteDigiMeshDevice>> {
+        let nt = self.get_value("NT").unwrap_or(0x82);
+        let mut cmd = AtCommands::Discover(None).create();
+        cmd.timeout = Duration::from_millis(nt * 100) + Duration::from_secs(2);
+        let lines = self.device.atcmd(&cmd)?;
+        if lines.first().map(|l| l.as_str()) == Some("ERROR") {
+            return Err(discover::Error::CommandError(
+                "ND".to_string(),
+                AtStatus::Error,
+            ));
+        }
+
+        Ok(lines
+            .split(|line| line.is_empty())
+            .filter_map(parse_node_lines)
+            .collect())
+    }
+
+    /// Leave command mode with CN
+    pub fn exit(mut self) -> discover::Result<()> {
+        self.active = false;
+        self.device.command_mode(false)
+    }
+}
+
+impl Drop for CommandSession<'_> {
+    fn drop(&mut self) {
+        if self.active {
+            let _ = self.device.command_mode(false);
+        }
+    }
+}
+
+/// Parse one ND record: MY, SH, SL, NI, parent, device type, status, profile
+/// and manufacturer, one per line
+fn parse_node_lines(lines: &[String]) -> Option<RemoteDigiMeshDevice> {
+    if lines.len() < 4 {
+        return None;
+    }
+    let sh = u64::from_str_radix(lines[1].trim(), 16).ok()?;
+    let sl = u64::from_str_radix(lines[2].trim(), 16).ok()?;
+    let role = lines
+        .get(5)
+        .and_then(|t| u8::from_str_radix(t.trim(), 16).ok())
+        .map(DeviceRole::from_device_type)
+        .unwrap_or(DeviceRole::Unknown);
+
+    Some(RemoteDigiMeshDevice {
+        addr_64bit: (sh << 32) | sl,
+        node_id: lines[3].clone(),
+        role,
+        vendor: None,
+        firmware_version: None,
+        hardware_version: None,
+        inventory: None,
+        durations: Vec::new(),
+        sleep_cycle: None,
+        sleep_learned: false,
+    })
+}
+
+/// Operating mode of the serial interface (AP)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApiMode {
+    Transparent,
+    Api,
+    ApiEscaped,
+}
+
+impl ApiMode {
+    pub fn code(&self) -> u8 {
+        match *self {
+            ApiMode::Transparent => 0,
+            ApiMode::Api => 1,
+            ApiMode::ApiEscaped => 2,
+        }
+    }
+
+    pub fn from_code(code: u64) -> Option<Self> {
+        match code {
+            0 => Some(ApiMode::Transparent),
+            1 => Some(ApiMode::Api),
+            2 => Some(ApiMode::ApiEscaped),
+            _ => None,
+        }
+    }
+}
+
+/// Read AP in command mode, which answers whatever the current mode is
+pub fn read_api_mode(device: &mut DigiMeshDevice) -> discover::Result<ApiMode> {
+    let mut session = CommandSession::enter(device)?;
+    let ap = session.get_value("AP")?;
+    session.exit()?;
+    ApiMode::from_code(ap)
+        .ok_or_else(|| discover::Error::InvalidMode(format!("Unknown API mode {}", ap)))
+}
+
+/// Set AP from command mode and save it with WR. Returns false when the module
+/// does not answer the command sequence.
+fn set_api_mode_in_command_mode(
+    device: &mut DigiMeshDevice,
+    mode: ApiMode,
+) -> discover::Result<bool> {
+    let mut session = match CommandSession::enter(device) {
+        Ok(session) => session,
+        Err(discover::Error::NoCommandMode) => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    if session.get_value("AP")? != mode.code() as u64 {
+        session.command("AP", Some(&mode.code().to_string()))?;
+        session.command("WR", None)?;
+    }
+    // the new mode takes effect when leaving command mode
+    session.exit()?;
+    Ok(true)
+}
+
+/// Switch the local module to API mode from command mode, or with API frames
+/// when it is already in API mode, save it with WR and check that it answers
+/// API frames. The frames of this crate are not escaped, so
+/// `ApiMode::ApiEscaped` is refused: the module would not understand them.
+pub fn enable_api_mode(device: &mut DigiMeshDevice, mode: ApiMode) -> discover::Result<()> {
+    match mode {
+        ApiMode::Transparent => return enable_transparent_mode(device),
+        ApiMode::ApiEscaped => {
+            return Err(discover::Error::InvalidMode(
+                "Escaped API mode is not supported".to_string(),
+            ))
+        }
+        ApiMode::Api => {}
+    }
+
+    if !set_api_mode_in_command_mode(device, mode)? {
+        // a module already in API mode may ignore the command sequence
+        match device.execute(NodeTarget::Local, "AP", Some(&[mode.code()])) {
+            // the response may be lost in the switch
+            Ok(_) | Err(discover::Error::Timeout) => {}
+            Err(err) => return Err(err),
+        }
+        device.execute(NodeTarget::Local, "WR", None)?;
+    }
+
+    let resp = device.at_command("AP", None, Duration::from_secs(1))?;
+    match resp.command_data {
+        Some(ref data) if data.last() == Some(&mode.code()) => Ok(()),
+        _ => Err(discover::Error::InvalidMode(
+            "Module did not switch to API mode".to_string(),
+        )),
+    }
+}
+
+/// Switch the local module back to transparent mode with an API frame, then
+/// save it with WR and check it from command mode
+pub fn enable_transparent_mode(device: &mut DigiMeshDevice) -> discover::Result<()> {
+    match device.execute(
+        NodeTarget::Local,
+        "AP",
+        Some(&[ApiMode::Transparent.code()]),
+    ) {
+        // the response may be lost in the switch
+        Ok(_) | Err(discover::Error::Timeout) => {}
+        Err(err) => return Err(err),
+    }
+
+    let mut session = CommandSession::enter(device)?;
+    if session.get_value("AP")? != ApiMode::Transparent.code() as u64 {
+        return Err(discover::Error::InvalidMode(
+            "Module did not switch to transparent mode".to_string(),
+        ));
+    }
+    session.command("WR", None)?;
+    session.exit()
+}
@@ -0,0 +1,323 @@
+#![allow(dead_code)]
+//!
+//! Message transport above the NP limit: fragmentation and reassembly
+//!
+//! Every fragment starts with a 9 byte header:
+//! magic (1), version (1), message id (2), fragment index (2), fragment count (2),
+//! checksum (1)
+//!
+//! The checksum makes the bytes of the fragment, header included, sum to 0xff
+//! as in API frames, so that application data starting with the magic byte is
+//! not taken for a fragment. Reassembly is bounded by `max_message_len`.
+//!
+
+use crate::api;
+use crate::discover::{self, DigiMeshDevice};
+use bytes::{BufMut, BytesMut};
+use rand::Rng;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+static MAGIC: u8 = 0xf7;
+static VERSION: u8 = 1;
+static HEADER_LEN: usize = 9;
+/// completed messages remembered to drop retransmitted fragments
+static RECENT_LEN: usize = 32;
+/// messages being reassembled at once, the oldest is dropped beyond
+static MAX_PARTIAL: usize = 16;
+
+/// fragments received of a message, stored as they come in
+struct Partial {
+    fragments: BTreeMap<usize, BytesMut>,
+    count: usize,
+    len: usize,
+    started: Instant,
+}
+
+impl Partial {
+    fn new(count: usize) -> Self {
+        Self {
+            fragments: BTreeMap::new(),
+            count,
+            len: 0,
+            started: Instant::now(),
+        }
+    }
+}
+
+pub struct Transport {
+    max_payload: usize,
+    next_msg_id: u16,
+    reassembly_timeout: Duration,
+    max_message_len: usize,
+    partial: HashMap<(u64, u16), Partial>,
+    recent: VecDeque<((u64, u16), Instant)>,
+}
+
+/// value making the bytes of the fragment sum to 0xff
+fn checksum(fragment: &[u8]) -> u8 {
+    0xff - fragment.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+}
+
+impl Transport {
+    /// Size fragments after the NP value of the local module
+    pub fn new(device: &mut DigiMeshDevice) -> discover::Result<Self> {
+        let max_payload = device.get_max_payload()?;
+        Ok(Self::with_max_payload(max_payload))
+    }
+
+    pub fn with_max_payload(max_payload: usize) -> Self {
+        let mut rng = rand::thread_rng();
+        Self {
+            max_payload: max_payload.max(HEADER_LEN + 1),
+            next_msg_id: rng.gen(),
+            reassembly_timeout: Duration::from_secs(30),
+            max_message_len: 1 << 20,
+            partial: HashMap::new(),
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Drop incomplete messages whose first fragment is older than `timeout`,
+    /// and forget completed ones after the same time
+    pub fn set_reassembly_timeout(&mut self, timeout: Duration) {
+        self.reassembly_timeout = timeout;
+    }
+
+    /// Drop messages longer than `len` bytes, 1 MiB by default
+    pub fn set_max_message_len(&mut self, len: usize) {
+        self.max_message_len = len;
+    }
+
+    /// payload bytes carried by each fragment
+    pub fn fragment_size(&self) -> usize {
+        self.max_payload - HEADER_LEN
+    }
+
+    /// Split `data` into fragments and send them one after the other to `addr`
+    pub fn send_message(
+        &mut self,
+        device: &mut DigiMeshDevice,
+        addr: u64,
+        data: &[u8],
+    ) -> discover::Result<()> {
+        for fragment in self.fragment(data)? {
+            device.send_data(addr, &fragment[..], None)?;
+        }
+        Ok(())
+    }
+
+    /// Split `data` into fragments carrying the next message id
+    fn fragment(&mut self, data: &[u8]) -> discover::Result<Vec<BytesMut>> {
+        let fragment_size = self.fragment_size();
+        let count = data.len().div_ceil(fragment_size).max(1);
+        if count > u16::MAX as usize {
+            return Err(discover::Error::ApiError(api::Error::PayloadError(
+                "Message exceeds max size".to_string(),
+            )));
+        }
+
+        let msg_id = self.next_msg_id;
+        self.next_msg_id = self.next_msg_id.wrapping_add(1);
+
+        let mut fragments = Vec::with_capacity(count);
+        for index in 0..count {
+            let start = index * fragment_size;
+            let end = (start + fragment_size).min(data.len());
+
+            let mut fragment = BytesMut::with_capacity(HEADER_LEN + end - start);
+            fragment.put_u8(MAGIC);
+            fragment.put_u8(VERSION);
+            fragment.put_u16(msg_id);
+            fragment.put_u16(index as u16);
+            fragment.put_u16(count as u16);
+            fragment.put_u8(0);
+            fragment.put(&data[start..end]);
+            fragment[HEADER_LEN - 1] = checksum(&fragment[..]);
+            fragments.push(fragment);
+        }
+        Ok(fragments)
+    }
+
+    /// Wait up to `timeout` for a complete message. Packets that do not carry a
+    /// fragment header are returned as they are.
+    pub fn recv_message(
+        &mut self,
+        device: &mut DigiMeshDevice,
+        timeout: Duration,
+    ) -> discover::Result<Option<(u64, Vec<u8>)>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.expire();
+            let remaining = match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) => remaining,
+                None => return Ok(None),
+            };
+            let packet = match device.recv_data(remaining)? {
+                Some(packet) => packet,
+                None => return Ok(None),
+            };
+            if let Some(message) = self.accept(packet.source_addr, &packet.data[..]) {
+                return Ok(Some(message));
+            }
+        }
+    }
+
+    /// Feed one received packet, returning the message it completes if any
+    pub fn accept(&mut self, source_addr: u64, data: &[u8]) -> Option<(u64, Vec<u8>)> {
+        if data.len() < HEADER_LEN || data[0] != MAGIC || data[1] != VERSION || checksum(data) != 0
+        {
+            return Some((source_addr, data.to_vec()));
+        }
+
+        let msg_id = u16::from_be_bytes([data[2], data[3]]);
+        let index = u16::from_be_bytes([data[4], data[5]]) as usize;
+        let count = u16::from_be_bytes([data[6], data[7]]) as usize;
+        let body = &data[HEADER_LEN..];
+        let key = (source_addr, msg_id);
+
+        self.expire();
+        if index >= count || self.recent.iter().any(|(k, _)| *k == key) {
+            return None;
+        }
+
+        if !self.partial.contains_key(&key) && self.partial.len() >= MAX_PARTIAL {
+            let oldest = self
+                .partial
+                .iter()
+                .min_by_key(|(_, p)| p.started)
+                .map(|(k, _)| *k);
+            if let Some(oldest) = oldest {
+                self.partial.remove(&oldest);
+            }
+        }
+        let partial = self
+            .partial
+            .entry(key)
+            .or_insert_with(|| Partial::new(count));
+        if partial.count != count {
+            // a message id reused by the sender for another message
+            *partial = Partial::new(count);
+        }
+        if let Entry::Vacant(fragment) = partial.fragments.entry(index) {
+            fragment.insert(BytesMut::from(body));
+            partial.len += body.len();
+        }
+        if partial.len > self.max_message_len {
+            self.partial.remove(&key);
+            return None;
+        }
+        if partial.fragments.len() < partial.count {
+            return None;
+        }
+
+        let partial = self.partial.remove(&key).unwrap();
+        if self.recent.len() >= RECENT_LEN {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((key, Instant::now()));
+
+        let mut message = Vec::with_capacity(partial.len);
+        for fragment in partial.fragments.values() {
+            message.extend_from_slice(&fragment[..]);
+        }
+        Some((source_addr, message))
+    }
+
+    /// Drop the incomplete messages and forget the completed ones older than
+    /// the reassembly timeout, so a message id used again after that is a new
+    /// message
+    fn expire(&mut self) {
+        let timeout = self.reassembly_timeout;
+        self.partial
+            .retain(|_, partial| partial.started.elapsed() < timeout);
+        self.recent
+            .retain(|(_, completed)| completed.elapsed() < timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn fragments_are_reassembled_in_any_order() {
+        let mut sender = Transport::with_max_payload(HEADER_LEN + 4);
+        let mut receiver = Transport::with_max_payload(HEADER_LEN + 4);
+        let data = message(10);
+
+        let fragments = sender.fragment(&data).unwrap();
+        assert_eq!(fragments.len(), 3);
+        assert!(fragments.iter().all(|f| f.len() <= HEADER_LEN + 4));
+
+        assert_eq!(receiver.accept(1, &fragments[2][..]), None);
+        assert_eq!(receiver.accept(1, &fragments[0][..]), None);
+        assert_eq!(receiver.accept(1, &fragments[1][..]), Some((1, data)));
+    }
+
+    #[test]
+    fn empty_message_is_one_fragment() {
+        let mut sender = Transport::with_max_payload(32);
+        let mut receiver = Transport::with_max_payload(32);
+
+        let fragments = sender.fragment(&[]).unwrap();
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(receiver.accept(1, &fragments[0][..]), Some((1, Vec::new())));
+    }
+
+    #[test]
+    fn retransmitted_fragment_is_dropped() {
+        let mut sender = Transport::with_max_payload(32);
+        let mut receiver = Transport::with_max_payload(32);
+
+        let fragments = sender.fragment(b"hello").unwrap();
+        assert_eq!(
+            receiver.accept(1, &fragments[0][..]),
+            Some((1, b"hello".to_vec()))
+        );
+        assert_eq!(receiver.accept(1, &fragments[0][..]), None);
+        // the same message id from another node is another message
+        assert_eq!(
+            receiver.accept(2, &fragments[0][..]),
+            Some((2, b"hello".to_vec()))
+        );
+    }
+
+    #[test]
+    fn message_id_is_reusable_after_the_reassembly_timeout() {
+        let mut sender = Transport::with_max_payload(32);
+        let mut receiver = Transport::with_max_payload(32);
+        receiver.set_reassembly_timeout(Duration::from_millis(0));
+
+        let fragments = sender.fragment(b"hello").unwrap();
+        assert!(receiver.accept(1, &fragments[0][..]).is_some());
+        assert!(receiver.accept(1, &fragments[0][..]).is_some());
+    }
+
+    #[test]
+    fn data_without_a_valid_header_is_passed_through() {
+        let mut receiver = Transport::with_max_payload(32);
+
+        // starts with the magic byte but the checksum does not match
+        let data = [MAGIC, VERSION, 0, 1, 0, 0, 0, 1, 0, b'x'];
+        assert_eq!(receiver.accept(1, &data), Some((1, data.to_vec())));
+        assert_eq!(receiver.accept(1, b"short"), Some((1, b"short".to_vec())));
+    }
+
+    #[test]
+    fn oversized_message_is_dropped() {
+        let mut sender = Transport::with_max_payload(HEADER_LEN + 4);
+        let mut receiver = Transport::with_max_payload(HEADER_LEN + 4);
+        receiver.set_max_message_len(8);
+
+        for fragment in sender.fragment(&message(12)).unwrap() {
+            assert_eq!(receiver.accept(1, &fragment[..]), None);
+        }
+        assert!(receiver.partial.is_empty());
+    }
+}